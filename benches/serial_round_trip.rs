@@ -0,0 +1,142 @@
+//! Round-trip latency of a command over a pty pair, against a fake controller that ACKs every
+//! packet as soon as it has read it.
+//!
+//! `event-driven` goes through `Dobot`, whose port is registered with the async reactor.
+//! `10 ms polling` is the former `Connector::read_packet`: a non-blocking read retried every
+//! 10 ms until the ACK has arrived.
+//!
+//! Run with `cargo bench --bench serial_round_trip`.
+
+use dobot_api::api::Dobot;
+use serialport::posix::TTYPort;
+use serialport::{SerialPort, SerialPortSettings};
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROUND_TRIPS: usize = 200;
+const DEVICE_NAME_ID: u8 = 1;
+
+/// Answers every packet with an ACK without params, like the controller does for most commands.
+fn serve(mut master: TTYPort) {
+    let mut header = [0u8; 3];
+    loop {
+        if read_exact(&mut master, &mut header).is_err() {
+            return;
+        }
+        if header[..2] != [0xAA, 0xAA] {
+            continue;
+        }
+        let mut payload = vec![0u8; header[2] as usize + 1];
+        if read_exact(&mut master, &mut payload).is_err() {
+            return;
+        }
+        let (id, ctrl) = (payload[0], payload[1]);
+        let checksum = 0u8.wrapping_sub(id.wrapping_add(ctrl));
+        if master
+            .write_all(&[0xAA, 0xAA, 2, id, ctrl, checksum])
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+fn read_exact(master: &mut TTYPort, buf: &mut [u8]) -> std::io::Result<()> {
+    let mut red = 0;
+    while red < buf.len() {
+        match master.read(&mut buf[red..]) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(size) => red += size,
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+async fn polling_round_trips(port_name: &str) -> Vec<Duration> {
+    let settings = SerialPortSettings {
+        timeout: Duration::from_millis(0),
+        ..SerialPortSettings::default()
+    };
+    let mut port = TTYPort::open(Path::new(port_name), &settings).unwrap();
+    let request = [
+        0xAA,
+        0xAA,
+        2,
+        DEVICE_NAME_ID,
+        0,
+        0u8.wrapping_sub(DEVICE_NAME_ID),
+    ];
+
+    let mut round_trips = Vec::with_capacity(ROUND_TRIPS);
+    for _ in 0..ROUND_TRIPS {
+        let start = Instant::now();
+        port.write_all(&request).unwrap();
+        let mut ack = [0u8; 6];
+        let mut red = 0;
+        while red < ack.len() {
+            match port.read(&mut ack[red..]) {
+                Ok(size) => red += size,
+                Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {
+                    tokio::time::delay_for(Duration::from_millis(10)).await
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        round_trips.push(start.elapsed());
+    }
+    round_trips
+}
+
+async fn event_driven_round_trips(port_name: &str) -> Vec<Duration> {
+    let dobot = Dobot::builder().port(port_name).connect().await.unwrap();
+
+    let mut round_trips = Vec::with_capacity(ROUND_TRIPS);
+    for _ in 0..ROUND_TRIPS {
+        let start = Instant::now();
+        dobot.get_device_name().await.unwrap();
+        round_trips.push(start.elapsed());
+    }
+    dobot.disconnect_dobot().await;
+    round_trips
+}
+
+fn report(name: &str, mut round_trips: Vec<Duration>) {
+    round_trips.sort();
+    let mean = round_trips.iter().sum::<Duration>() / round_trips.len() as u32;
+    let percentile = |p: usize| round_trips[(round_trips.len() - 1) * p / 100];
+    println!(
+        "{:<14} mean {:>10.3?}  p50 {:>10.3?}  p99 {:>10.3?}  max {:>10.3?}",
+        name,
+        mean,
+        percentile(50),
+        percentile(99),
+        round_trips[round_trips.len() - 1],
+    );
+}
+
+fn main() {
+    let (mut master, mut slave) = TTYPort::pair().unwrap();
+    master.set_timeout(Duration::from_secs(1)).unwrap();
+    let port_name = slave.name().unwrap();
+    thread::spawn(move || serve(master));
+
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    // Both designs open the port exclusively, and the flag outlives them as long as `slave` is open.
+    slave.set_exclusive(false).unwrap();
+    let polling = runtime.block_on(polling_round_trips(&port_name));
+    slave.set_exclusive(false).unwrap();
+    let event_driven = runtime.block_on(event_driven_round_trips(&port_name));
+
+    println!("{} round trips over {}", ROUND_TRIPS, port_name);
+    report("10 ms polling", polling);
+    report("event-driven", event_driven);
+}
@@ -1,11 +1,8 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use proc_macro2::TokenTree;
 use quote::quote;
-use std::any::Any;
 use syn;
-use syn::spanned::Spanned;
 
 #[proc_macro_derive(ToParams)]
 pub fn to_params_derive(input: TokenStream) -> TokenStream {
@@ -48,3 +45,37 @@ fn impl_to_params(ast: &syn::DeriveInput) -> TokenStream {
     };
     gen.into()
 }
+
+#[proc_macro_derive(FromParams)]
+pub fn from_params_derive(input: TokenStream) -> TokenStream {
+    let ast = syn::parse(input).unwrap();
+
+    impl_from_params(&ast)
+}
+
+fn impl_from_params(ast: &syn::DeriveInput) -> TokenStream {
+    let name = &ast.ident;
+    let fields = match &ast.data {
+        syn::Data::Struct(data) => data.fields.iter(),
+        _ => panic!("only struct"),
+    };
+
+    let field_ident = fields.map(|f| {
+        let ident = f.ident.as_ref().unwrap();
+
+        quote! {
+            #ident: FromParamable::from_params(rdr),
+        }
+    });
+
+    let gen = quote! {
+        impl FromParamable for #name {
+            fn from_params(rdr: &mut Cursor<&[u8]>) -> Self {
+                Self {
+                    #(#field_ident)*
+                }
+            }
+        }
+    };
+    gen.into()
+}
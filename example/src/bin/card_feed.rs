@@ -1,17 +1,10 @@
-use lazy_static::lazy_static;
-
-use dobot_api::api::types::{EndEffectorSuctionCapState, HHTTrigMode, PTPCmd, PTPCommonParams};
+use dobot_api::api::command::SetEndEffectorSuctionCup;
+use dobot_api::api::types::{EndEffectorSuctionCapState, PTPCmd};
 use dobot_api::api::Dobot;
-use futures::future::BoxFuture;
-use futures::future::FutureExt;
-use std::time::Duration;
-use tokio::prelude::*;
-use tokio::runtime::Runtime;
-use tokio::time::delay_for;
 
 #[tokio::main]
 async fn main() {
-    let dobot = Dobot::connect_dobot(None, None, None, None).unwrap();
+    let dobot = Dobot::builder().connect().await.unwrap();
     let ptp_deck_position = PTPCmd {
         ptp_mode: 0u8,
         x: 300.13538,
@@ -40,40 +33,35 @@ async fn main() {
         z: 0.0,
         r: -5.9823236,
     };
+    dobot.set_lost_step_params(5.0).await.unwrap();
+    loop {
+        dobot.set_queued_cmd_start_exec().await.unwrap();
+        dobot.move_to(ptp_relay1).accepted().await.unwrap();
+        dobot.move_to(ptp_deck_position).accepted().await.unwrap();
+        if let Err(e) = dobot.detect_lost_step().await_done().await {
+            eprintln!("stop feeding: {:?}", e);
+            break;
+        }
+        dobot
+            .suction_cap(EndEffectorSuctionCapState::In)
+            .await_done()
+            .await
+            .unwrap();
+        dobot.move_to(ptp_relay1).accepted().await.unwrap();
+        dobot.move_to(ptp_relay2).accepted().await.unwrap();
+        dobot.move_to(ptp_card_position).accepted().await.unwrap();
+        if let Err(e) = dobot.detect_lost_step().await_done().await {
+            eprintln!("stop feeding: {:?}", e);
+            break;
+        }
+        dobot
+            .suction_cap(EndEffectorSuctionCapState::Out)
+            .await_done()
+            .await
+            .unwrap();
+    }
     dobot
-        .start(
-            async {
-                loop {
-                    dobot.set_queued_cmd_start_exec().await.unwrap();
-                    let _ = dobot.set_ptp_cmd(ptp_relay1, true).await.unwrap().unwrap();
-                    let index = dobot
-                        .set_ptp_cmd(ptp_deck_position, true)
-                        .await
-                        .unwrap()
-                        .unwrap();
-                    dobot.wait_queued_command(index).await;
-                    let index = dobot
-                        .set_end_effector_suctions_cap(EndEffectorSuctionCapState::In, true)
-                        .await
-                        .unwrap()
-                        .unwrap();
-                    dobot.wait_queued_command(index).await;
-                    let _ = dobot.set_ptp_cmd(ptp_relay1, true).await.unwrap().unwrap();
-                    let _ = dobot.set_ptp_cmd(ptp_relay2, true).await.unwrap().unwrap();
-                    let _ = dobot
-                        .set_ptp_cmd(ptp_card_position, true)
-                        .await
-                        .unwrap()
-                        .unwrap();
-                    let index = dobot
-                        .set_end_effector_suctions_cap(EndEffectorSuctionCapState::Out, true)
-                        .await
-                        .unwrap()
-                        .unwrap();
-                    dobot.wait_queued_command(index).await;
-                }
-            }
-            .boxed(),
-        )
-        .await;
+        .execute::<SetEndEffectorSuctionCup>(EndEffectorSuctionCapState::Off.into())
+        .await
+        .unwrap();
 }
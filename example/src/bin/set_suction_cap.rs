@@ -1,32 +1,21 @@
-use lazy_static::lazy_static;
-
-use dobot_api::api::types::{EndEffectorSuctionCapState, HHTTrigMode, PTPCmd, PTPCommonParams};
+use dobot_api::api::types::EndEffectorSuctionCapState;
 use dobot_api::api::Dobot;
-use futures::future::BoxFuture;
-use futures::future::FutureExt;
 use std::time::Duration;
-use tokio::prelude::*;
-use tokio::runtime::Runtime;
 use tokio::time::delay_for;
 
 #[tokio::main]
 async fn main() {
-    let dobot = Dobot::connect_dobot(None, None, None, None).unwrap();
+    let dobot = Dobot::builder().connect().await.unwrap();
+    dobot.set_queued_cmd_start_exec().await.unwrap();
+    dobot
+        .suction_cap(EndEffectorSuctionCapState::In)
+        .accepted()
+        .await
+        .unwrap();
+    delay_for(Duration::from_secs(5)).await;
     dobot
-        .start(
-            async {
-                dobot.set_queued_cmd_start_exec().await.unwrap();
-                dobot
-                    .set_end_effector_suctions_cap(EndEffectorSuctionCapState::In, true)
-                    .await
-                    .unwrap();
-                delay_for(Duration::from_secs(5)).await;
-                dobot
-                    .set_end_effector_suctions_cap(EndEffectorSuctionCapState::Off, true)
-                    .await
-                    .unwrap();
-            }
-            .boxed(),
-        )
-        .await;
+        .suction_cap(EndEffectorSuctionCapState::Off)
+        .accepted()
+        .await
+        .unwrap();
 }
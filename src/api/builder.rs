@@ -0,0 +1,214 @@
+use crate::api::types::FirmwareMode;
+use crate::api::{Dobot, DobotError, Result, RetryPolicy};
+use crate::connector::{Connector, Transport};
+use crate::recording::{DeferredSink, RecordFormat, Recorder, RecordingError, Replay};
+use crate::runtime::{default_runtime, Runtime};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Connects to a Dobot, e.g.
+/// `Dobot::builder().device_name("left").ack_timeout(timeout).connect().await`.
+///
+/// Without a port every USB serial adapter that looks like a Dobot is tried in turn. The serial
+/// number, device name and firmware filters are checked over the wire, so they pick the right arm
+/// even when several are plugged in.
+#[derive(Default)]
+pub struct DobotBuilder {
+    port_name: Option<String>,
+    baud_rate: Option<u32>,
+    serial_number: Option<String>,
+    device_name: Option<String>,
+    firmware: Option<FirmwareMode>,
+    ack_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    transport: Option<Box<dyn Transport>>,
+    runtime: Option<Arc<dyn Runtime>>,
+    record: Option<(PathBuf, RecordFormat)>,
+    replay: Option<PathBuf>,
+}
+
+impl DobotBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn port(mut self, port_name: &str) -> Self {
+        self.port_name = Some(port_name.to_string());
+        self
+    }
+
+    /// Defaults to 115200.
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = Some(baud_rate);
+        self
+    }
+
+    pub fn serial_number(mut self, serial_number: &str) -> Self {
+        self.serial_number = Some(serial_number.to_string());
+        self
+    }
+
+    /// Only connects to an arm named `device_name` with `Dobot::set_device_name`.
+    pub fn device_name(mut self, device_name: &str) -> Self {
+        self.device_name = Some(device_name.to_string());
+        self
+    }
+
+    /// Only connects to an arm running `firmware`, typically `FirmwareMode::Dobot`.
+    pub fn firmware(mut self, firmware: FirmwareMode) -> Self {
+        self.firmware = Some(firmware);
+        self
+    }
+
+    /// How long to wait for each ACK. Takes precedence over the timeout of `retry_policy`.
+    pub fn ack_timeout(mut self, ack_timeout: Duration) -> Self {
+        self.ack_timeout = Some(ack_timeout);
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// The executor to run the connection on. Defaults to `TokioRuntime` or `AsyncStdRuntime`,
+    /// whichever feature is enabled, and must be given if neither is.
+    pub fn runtime(mut self, runtime: Arc<dyn Runtime>) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Writes every packet exchanged with the arm to `path`, which is overwritten once the arm is
+    /// found. Failing to write the recording stops it, but not the connection.
+    pub fn record(mut self, path: impl AsRef<Path>, format: RecordFormat) -> Self {
+        self.record = Some((path.as_ref().to_path_buf(), format));
+        self
+    }
+
+    /// Plays the recording at `path` back instead of connecting to an arm. Either format is
+    /// read.
+    pub fn replay(mut self, path: impl AsRef<Path>) -> Self {
+        self.replay = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Talks over `transport` instead of opening a serial port, e.g. a TCP bridge to the arm.
+    /// The device filters are still checked against what the arm answers over it.
+    pub fn transport(mut self, transport: Box<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Connects to the first device that matches every filter. Fails with
+    /// `DobotError::PortNotFound` if there is none.
+    pub async fn connect(mut self) -> Result<Dobot> {
+        let runtime = self
+            .runtime
+            .take()
+            .or_else(default_runtime)
+            .ok_or(DobotError::NoRuntime)?;
+        let mut retry_policy = self.retry_policy;
+        if let Some(ack_timeout) = self.ack_timeout {
+            retry_policy.timeout = ack_timeout;
+        }
+
+        if let Some(path) = &self.replay {
+            let replay = Replay::new(File::open(path).map_err(DobotError::Io)?, runtime.clone())
+                .map_err(DobotError::Io)?;
+            self.transport = Some(Box::new(replay));
+        }
+        if let Some(transport) = self.transport.take() {
+            let (transport, sink, recording_error) = self.recorded(transport)?;
+            let dobot = Dobot::from_transport(transport, retry_policy, runtime, recording_error);
+            return if self.matches(&dobot).await? {
+                self.persist(sink)?;
+                Ok(dobot)
+            } else {
+                Err(DobotError::PortNotFound)
+            };
+        }
+
+        let baud_rate = self.baud_rate.unwrap_or(115200);
+        let port_names = match &self.port_name {
+            Some(port_name) => vec![port_name.clone()],
+            None => Dobot::search_dobot(),
+        };
+        let mut error = DobotError::PortNotFound;
+        for port_name in port_names {
+            let connector = match Connector::connect(
+                port_name.as_str(),
+                baud_rate,
+                None,
+                None,
+                runtime.clone(),
+            ) {
+                Ok(connector) => connector,
+                Err(e) => {
+                    error = DobotError::ConnectorError(e);
+                    continue;
+                }
+            };
+            let (transport, sink, recording_error) = self.recorded(Box::new(connector))?;
+            let dobot =
+                Dobot::from_transport(transport, retry_policy, runtime.clone(), recording_error);
+            match self.matches(&dobot).await {
+                Ok(true) => {
+                    self.persist(sink)?;
+                    return Ok(dobot);
+                }
+                Ok(false) => error = DobotError::PortNotFound,
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Wraps `transport` in a recorder that holds the recording back until `persist`.
+    fn recorded(
+        &self,
+        transport: Box<dyn Transport>,
+    ) -> Result<(Box<dyn Transport>, Option<DeferredSink>, RecordingError)> {
+        match &self.record {
+            Some((_, format)) => {
+                let sink = DeferredSink::default();
+                let recorder = Recorder::new(transport, Box::new(sink.clone()), *format)
+                    .map_err(DobotError::Io)?;
+                let error = recorder.error();
+                Ok((Box::new(recorder), Some(sink), error))
+            }
+            None => Ok((transport, None, RecordingError::default())),
+        }
+    }
+
+    /// Creates the recording file for the port that was picked.
+    fn persist(&self, sink: Option<DeferredSink>) -> Result<()> {
+        match (&self.record, sink) {
+            (Some((path, _)), Some(sink)) => {
+                let file = File::create(path).map_err(DobotError::Io)?;
+                sink.persist(Box::new(file)).map_err(DobotError::Io)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn matches(&self, dobot: &Dobot) -> Result<bool> {
+        if let Some(serial_number) = &self.serial_number {
+            if dobot.get_device_sn().await? != *serial_number {
+                return Ok(false);
+            }
+        }
+        if let Some(device_name) = &self.device_name {
+            if dobot.get_device_name().await? != *device_name {
+                return Ok(false);
+            }
+        }
+        if let Some(firmware) = self.firmware {
+            if dobot.get_firmware_mode().await? != firmware {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
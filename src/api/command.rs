@@ -0,0 +1,187 @@
+//! The commands of the protocol as types, so that what a command is sent with and answered with
+//! is checked at compile time.
+//!
+//! `dobot.execute::<GetPose>(()).await?` reads the pose, and
+//! `dobot.execute_queued::<SetPTPCmd>(ptp_cmd)` queues a move. Queuing a command the controller
+//! does not queue, such as `GetPose`, does not compile.
+
+use crate::api::types::{
+    AlarmsState, AngleSensorCoef, AngleSensorStaticError, DeviceVersion, EndEffectorParams,
+    EndEffectorSuctionCapParams, FirmwareMode, FirmwareSwitch, HHTTrigMode, IODIParams, Kinematics,
+    PTPCmd, PTPCommonParams, Pose, ResetPoseParams, UART4PeripheralsModel,
+};
+#[cfg(feature = "servo-tuning")]
+use crate::api::types::{ServoControlLoop, ServoPIDParams};
+use crate::api::{Dobot, DobotError, QueueIndex, QueuedCommand, Result};
+use crate::communicator::CommunicateStatus;
+use crate::protocol::message::{FromParams, Message, ReadWrite, ToParams};
+use crate::protocol::protocol_id::ProtocolID;
+
+pub trait Command {
+    const ID: ProtocolID;
+    const RW: ReadWrite;
+    type Params: ToParams;
+    type Response: FromParams;
+}
+
+/// A command the controller takes into its queue, see `Dobot::execute_queued`.
+pub trait QueueableCommand: Command {}
+
+macro_rules! commands {
+    (@queueable $name:ident) => {};
+    (@queueable $name:ident queueable) => {
+        impl QueueableCommand for $name {}
+    };
+    (@is_queueable) => { false };
+    (@is_queueable queueable) => { true };
+    ($(
+        $(#[$meta:meta])*
+        $name:ident: $rw:ident $id:ident, $params:ty => $response:ty $(, $queueable:ident)?;
+    )*) => {
+        $(
+            $(#[$meta])*
+            pub struct $name;
+
+            $(#[$meta])*
+            impl Command for $name {
+                const ID: ProtocolID = ProtocolID::$id;
+                const RW: ReadWrite = ReadWrite::$rw;
+                type Params = $params;
+                type Response = $response;
+            }
+
+            commands!(@queueable $name $($queueable)?);
+        )*
+
+        #[cfg(test)]
+        fn for_each_command(mut f: impl FnMut(ProtocolID, ReadWrite, bool)) {
+            $(
+                $(#[$meta])*
+                f($name::ID, $name::RW, commands!(@is_queueable $($queueable)?));
+            )*
+        }
+    };
+}
+
+commands! {
+    GetDeviceSN: Read ProtocolDeviceSN, () => String;
+    SetDeviceName: Write ProtocolDeviceName, String => ();
+    GetDeviceName: Read ProtocolDeviceName, () => String;
+    GetDeviceVersion: Read ProtocolDeviceVersion, () => DeviceVersion;
+
+    GetPose: Read ProtocolGetPose, () => Pose;
+    ResetPose: Write ProtocolResetPose, ResetPoseParams => ();
+    GetKinematics: Read ProtocolGetKinematics, () => Kinematics;
+
+    GetAlarmsState: Read ProtocolAlarmsState, () => AlarmsState;
+    ClearAllAlarmsState: Write ProtocolAlarmsState, () => ();
+
+    SetHHTTrigMode: Write ProtocolHHTTrigMode, HHTTrigMode => ();
+    SetHHTTrigOutputEnabled: Write ProtocolHHTTrigOutputEnabled, bool => ();
+    GetHHTTrigOutput: Read ProtocolHHTTrigOutput, () => bool;
+
+    GetIODI: Read ProtocolIODI, u8 => IODIParams;
+
+    SetEndEffectorParams: Write ProtocolEndEffectorParams, EndEffectorParams => (), queueable;
+    SetEndEffectorSuctionCup:
+        Write ProtocolEndEffectorSuctionCup, EndEffectorSuctionCapParams => (), queueable;
+    GetEndEffectorSuctionCup:
+        Read ProtocolEndEffectorSuctionCup, () => EndEffectorSuctionCapParams;
+
+    SetPTPCommonParams: Write ProtocolPTPCommonParams, PTPCommonParams => (), queueable;
+    SetPTPCmd: Write ProtocolPTPCmd, PTPCmd => (), queueable;
+
+    SetWAITCmd: Write ProtocolWAITCmd, u32 => (), queueable;
+
+    SetAngleSensorStaticError: Write ProtocolAngleSensorStaticError, AngleSensorStaticError => ();
+    GetAngleSensorStaticError: Read ProtocolAngleSensorStaticError, () => AngleSensorStaticError;
+    SetAngleSensorCoef: Write ProtocolAngleSensorCoef, AngleSensorCoef => ();
+    GetAngleSensorCoef: Read ProtocolAngleSensorCoef, () => AngleSensorCoef;
+    SetBaseDecoderStaticError: Write ProtocolBaseDecoderStaticError, f32 => ();
+    GetBaseDecoderStaticError: Read ProtocolBaseDecoderStaticError, () => f32;
+
+    SetFirmwareSwitch: Write ProtocolFirmwareSwitch, FirmwareSwitch => ();
+    GetFirmwareMode: Read ProtocolFirmwareMode, () => FirmwareMode;
+
+    SetLostStepParams: Write ProtocolLostStepSet, f32 => ();
+    SetLostStepCmd: Write ProtocolLostStepDetect, () => (), queueable;
+
+    GetUART4PeripheralsModel: Read ProtocolCheckUART4PeripheralsModel, () => UART4PeripheralsModel;
+    SetUART4PeripheralsEnabled: Write ProtocolUART4PeripheralsEnabled, bool => ();
+    GetUART4PeripheralsEnabled: Read ProtocolUART4PeripheralsEnabled, () => bool;
+
+    SetPulseModeEnabled: Write ProtocolFunctionPulseMode, bool => ();
+    GetPulseModeEnabled: Read ProtocolFunctionPulseMode, () => bool;
+
+    // Answered with the milliseconds the given move would take.
+    GetPTPTime: Read ProtocolPTPTime, PTPCmd => u32;
+    #[cfg(feature = "servo-tuning")]
+    SetServoPIDParams: Write ProtocolServoPIDParams, ServoPIDParams => ();
+    #[cfg(feature = "servo-tuning")]
+    GetServoPIDParams: Read ProtocolServoPIDParams, ServoControlLoop => ServoPIDParams;
+    #[cfg(feature = "servo-tuning")]
+    SetServoControlLoop: Write ProtocolServoControlLoop, ServoControlLoop => ();
+    #[cfg(feature = "servo-tuning")]
+    SaveServoPIDParams: Write ProtocolSaveServoPIDParams, ServoControlLoop => ();
+
+    SetQueuedCmdStartExec: Write ProtocolQueuedCmdStartExec, () => ();
+    SetQueuedCmdStopExec: Write ProtocolQueuedCmdStopExec, () => ();
+    SetQueuedCmdForceStopExec: Write ProtocolQueuedCmdForceStopExec, () => ();
+    SetQueuedCmdClear: Write ProtocolQueuedCmdClear, () => ();
+    GetQueuedCmdCurrentIndex: Read ProtocolQueuedCmdCurrentIndex, () => QueueIndex;
+}
+
+impl Dobot {
+    /// Sends `C` right away and waits for its response.
+    pub async fn execute<C: Command>(&self, params: C::Params) -> Result<C::Response> {
+        let mes = Message::try_new(C::ID, C::RW, false, &Some(params)).map_err(DobotError::Io)?;
+
+        let status = self.send_command_message_and_wait_execution(&mes).await;
+
+        match status {
+            CommunicateStatus::NoError(message) => Ok(C::Response::from_params(
+                message.params_len as usize,
+                message.params,
+            )),
+            _ => Err(status.into()),
+        }
+    }
+
+    /// Puts `C` into the controller's command queue.
+    ///
+    /// ```compile_fail
+    /// use dobot_api::api::command::GetPose;
+    /// use dobot_api::api::Dobot;
+    ///
+    /// fn queue_a_read(dobot: &Dobot) {
+    ///     let _ = dobot.execute_queued::<GetPose>(());
+    /// }
+    ///
+    /// fn main() {
+    ///     let _ = queue_a_read as fn(&Dobot);
+    /// }
+    /// ```
+    pub fn execute_queued<C: QueueableCommand>(&self, params: C::Params) -> QueuedCommand {
+        QueuedCommand::new(self, Message::new(C::ID, C::RW, true, &Some(params)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_agree_with_the_descriptors() {
+        let mut count = 0;
+        for_each_command(|id, rw, queueable| {
+            let descriptor = id.descriptor();
+            match rw {
+                ReadWrite::Read => assert!(descriptor.readable, "{}", id),
+                ReadWrite::Write => assert!(descriptor.writable, "{}", id),
+            }
+            assert!(!queueable || descriptor.queueable, "{}", id);
+            count += 1;
+        });
+        assert!(count > 0);
+    }
+}
@@ -1,125 +1,302 @@
 use crate::api::types::{
-    EndEffectorParams, EndEffectorSuctionCapParams, EndEffectorSuctionCapState, HHTTrigMode,
-    PTPCmd, PTPCommonParams, Pose,
+    AlarmsState, AngleSensorCoef, AngleSensorStaticError, CalibrationParams, DeviceVersion,
+    EndEffectorParams, EndEffectorSuctionCapState, FirmwareMode, FirmwareSwitch, HHTTrigMode,
+    Kinematics, PTPCmd, PTPCommonParams, Pose, ResetPoseParams, UART4PeripheralsModel,
 };
-use crate::api::DobotError::CommunicationError;
-use crate::communicator::{CommunicateStatus, Communicator};
-use crate::connector::{Connector, ConnectorError};
-use crate::protocol::message::{FromParams, Message, ReadWrite};
-use crate::protocol::protocol_id::ProtocolID;
-use futures::channel::oneshot;
+#[cfg(feature = "servo-tuning")]
+use crate::api::types::{ServoControlLoop, ServoPIDParams};
+use crate::communicator::{CommunicateStatus, Communicator, CommunicatorHandle};
+pub use crate::communicator::{Diagnostics, RetryPolicy};
+pub use crate::connector::{ConnectorError, Transport};
+use crate::protocol::message::{FromParams, Message, PARAMS_SIZE};
+pub use crate::protocol::packet::Packet;
+use crate::recording::RecordingError;
+use crate::runtime::{timeout, Runtime};
+use futures::channel::{mpsc, oneshot};
 use futures::future::join_all;
+use futures::lock::Mutex;
 use serialport::SerialPortType::UsbPort;
-use std::sync::Arc;
+use std::future::Future;
+use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Duration;
-use tokio::sync::RwLock;
-use tokio::time::delay_for;
 
-use futures::future::{BoxFuture, FutureExt};
+use futures::future::FutureExt;
+use futures::stream::{self, Stream, StreamExt};
 use futures::{pin_mut, select};
 
+mod builder;
+pub mod command;
+mod queued;
+mod telemetry;
 pub mod types;
 
+pub use builder::DobotBuilder;
+use command::*;
+pub use queued::QueuedCommand;
+pub use telemetry::StateSnapshot;
+use telemetry::StateSubscriber;
+
 #[derive(Debug)]
 pub enum DobotError {
     CommunicationError(CommunicateStatus),
     ConnectorError(ConnectorError),
     PortNotFound,
+    LostStep(AlarmsState),
+    /// The arm raised an alarm while a queued command was waited for.
+    Alarm(AlarmsState),
+    /// The command queue was stopped before the controller got to the waited for command.
+    QueueStopped,
+    /// The command queue was cleared before the controller got to the waited for command.
+    QueueCleared,
+    Io(std::io::Error),
+    Disconnected,
+    Cancelled,
+    /// The controller did not get to a queued command within the time given to wait for it.
+    Timeout,
+    /// No runtime feature is enabled and `DobotBuilder::runtime` was not called either.
+    NoRuntime,
+    /// The controller came back from a firmware switch running another firmware.
+    FirmwareNotSwitched(FirmwareMode),
+}
+
+impl From<CommunicateStatus> for DobotError {
+    fn from(status: CommunicateStatus) -> Self {
+        match status {
+            CommunicateStatus::IoError(e) => DobotError::Io(e),
+            CommunicateStatus::Disconnected => DobotError::Disconnected,
+            CommunicateStatus::Cancelled => DobotError::Cancelled,
+            _ => DobotError::CommunicationError(status),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, DobotError>;
 
+/// A connection to one arm. Clones share the connection, which is served by a driver task
+/// spawned on the runtime chosen with `DobotBuilder::runtime` and shut down once the last clone
+/// is dropped or `disconnect_dobot` is called.
+#[derive(Clone)]
 pub struct Dobot {
-    communicator: Arc<RwLock<Communicator>>,
-    checking_queue_indices: Arc<RwLock<Vec<(QueueIndex, oneshot::Sender<QueueIndex>)>>>,
+    handle: CommunicatorHandle,
+    checking_queue_indices: Arc<Mutex<Vec<QueueIndexWaiter>>>,
+    // Wakes the queue index poller up when a waiter is added.
+    waiter_added: mpsc::UnboundedSender<()>,
+    state_subscribers: Arc<StdMutex<Vec<StateSubscriber>>>,
+    subscriber_added: mpsc::UnboundedSender<()>,
+    retry_policy: RetryPolicy,
+    runtime: Arc<dyn Runtime>,
+    recording_error: RecordingError,
+    // `None` for the driver's own handle, which must not keep the driver alive.
+    driver: Option<Arc<Driver>>,
+}
+
+struct Driver {
+    shutdown: StdMutex<Option<oneshot::Sender<()>>>,
+    stopped: StdMutex<Option<oneshot::Receiver<()>>>,
 }
 
 #[derive(PartialOrd, PartialEq, Debug, Copy, Clone)]
-pub struct QueueIndex(u64);
+pub struct QueueIndex(pub(crate) u64);
 
-type ResultQueueIndex = Result<Option<QueueIndex>>;
+impl FromParams for QueueIndex {
+    fn from_params(size: usize, params: [u8; PARAMS_SIZE]) -> Self {
+        QueueIndex(u64::from_params(size, params))
+    }
+}
 
-impl Dobot {
-    pub async fn start(&self, dobot_main_future: BoxFuture<'_, ()>) {
-        let dm = dobot_main_future.fuse();
-        let start = self.start_communicator_loop().fuse();
-        let cq = self.check_queue_index_loop().fuse();
+// The alarms already raised when the waiter registered do not fail it.
+type QueueIndexWaiter = (QueueIndex, AlarmsState, oneshot::Sender<Result<QueueIndex>>);
 
-        pin_mut!(start, dm, cq);
+impl Driver {
+    fn shutdown(&self) {
+        if let Some(shutdown) = self.shutdown.lock().unwrap().take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Dobot {
+    /// Serves the connection until `shutdown` fires or its sender is dropped. Dropping the
+    /// communicator closes the port and fails every request still pending with `Disconnected`.
+    async fn drive(
+        self,
+        mut communicator: Communicator,
+        waiter_added: mpsc::UnboundedReceiver<()>,
+        subscriber_added: mpsc::UnboundedReceiver<()>,
+        shutdown: oneshot::Receiver<()>,
+        stopped: oneshot::Sender<()>,
+    ) {
+        {
+            let communicator_loop = async { while communicator.run().await {} }.fuse();
+            let cq = self.check_queue_index_loop(waiter_added).fuse();
+            let telemetry = self.publish_state_loop(subscriber_added).fuse();
+            let shutdown = shutdown.fuse();
+            pin_mut!(communicator_loop, cq, telemetry, shutdown);
 
-        loop {
             select! {
-                () = start => {
-                },
-                () = cq => {
-                },
-                () = dm => {
-                    break;
-                },
+                () = communicator_loop => {},
+                () = cq => {},
+                () = telemetry => {},
+                _ = shutdown => {},
             }
         }
+        drop(communicator);
+        self.fail_queue_waiters(|| DobotError::Disconnected).await;
+        self.close_state_subscriptions();
+        let _ = stopped.send(());
     }
 
-    async fn start_communicator_loop(&self) {
+    async fn check_queue_index_loop(&self, mut waiter_added: mpsc::UnboundedReceiver<()>) {
         loop {
-            self.communicator.write().await.run().await;
-            delay_for(Duration::from_millis(10)).await;
-        }
-    }
+            // Polling with nobody waiting would only crowd the serial line.
+            if self.remove_dropped_waiters().await {
+                if waiter_added.next().await.is_none() {
+                    return;
+                }
+                continue;
+            }
 
-    async fn check_queue_index_loop(&self) {
-        loop {
-            let queue_index = self
-                .get_queue_index()
-                .await
-                .expect("some error when get_queue_index");
+            let polled = match self.get_queue_index().await {
+                Ok(queue_index) => self
+                    .get_alarms_state()
+                    .await
+                    .map(|alarms_state| (queue_index, alarms_state)),
+                Err(e) => Err(e),
+            };
+            let (queue_index, alarms_state) = match polled {
+                Ok(polled) => polled,
+                Err(e) => {
+                    // Anything else, e.g. a timeout, is transient and retried on the next poll.
+                    match e {
+                        DobotError::Io(e) => {
+                            self.fail_queue_waiters(|| {
+                                DobotError::Io(std::io::Error::new(e.kind(), e.to_string()))
+                            })
+                            .await
+                        }
+                        DobotError::Disconnected => {
+                            self.fail_queue_waiters(|| DobotError::Disconnected).await;
+                            return;
+                        }
+                        _ => {}
+                    }
+                    self.runtime.delay(Duration::from_millis(10)).await;
+                    continue;
+                }
+            };
+
+            // Not held across the delay, the only point where other tasks get to take it.
+            {
+                let mut chi = self.checking_queue_indices.lock().await;
 
-            let mut chi = self.checking_queue_indices.write().await;
-            let mut i = 0;
+                let mut i = 0;
 
-            while i < chi.len() {
-                if queue_index >= chi[i].0 {
-                    let c = chi.remove(i);
-                    c.1.send(queue_index);
-                } else {
-                    i += 1;
+                while i < chi.len() {
+                    let raised = alarms_state.raised_since(&chi[i].1);
+                    if raised.has_lost_step() {
+                        // Checked first: a lost step check raises it as it executes, so the
+                        // same poll may see the command done.
+                        let (_, _, sender) = chi.remove(i);
+                        let _ = sender.send(Err(DobotError::LostStep(raised)));
+                    } else if queue_index >= chi[i].0 {
+                        let (_, _, sender) = chi.remove(i);
+                        let _ = sender.send(Ok(queue_index));
+                    } else if raised.any() {
+                        // The command may never be executed, so the waiter is not left hanging.
+                        let (_, _, sender) = chi.remove(i);
+                        let _ = sender.send(Err(DobotError::Alarm(raised)));
+                    } else {
+                        // Alarms cleared since count as new once they are raised again.
+                        chi[i].1 = alarms_state;
+                        i += 1;
+                    }
                 }
             }
-            delay_for(Duration::from_millis(10)).await;
+            self.runtime.delay(Duration::from_millis(10)).await;
         }
     }
 
-    pub async fn wait_queued_command(&self, index: QueueIndex) {
-        let (tx, rx) = oneshot::channel::<QueueIndex>();
-        self.checking_queue_indices.write().await.push((index, tx));
-        rx.await;
+    /// Forgets the waiters whose future was dropped, e.g. on a timeout. Returns whether none are
+    /// left.
+    async fn remove_dropped_waiters(&self) -> bool {
+        let mut chi = self.checking_queue_indices.lock().await;
+        chi.retain(|(_, _, sender)| !sender.is_canceled());
+        chi.is_empty()
     }
 
-    pub async fn wait_queued_commands(&self, indices: &Vec<QueueIndex>) {
-        join_all(indices.iter().map(|x| self.wait_queued_command(*x))).await;
+    async fn fail_queue_waiters(&self, error: impl Fn() -> DobotError) {
+        for (_, _, sender) in self.checking_queue_indices.lock().await.drain(..) {
+            let _ = sender.send(Err(error()));
+        }
     }
 
-    pub async fn get_queue_index(&self) -> Result<QueueIndex> {
-        let mes = Message::new::<()>(
-            ProtocolID::ProtocolQueuedCmdCurrentIndex,
-            ReadWrite::Read,
-            false,
-            &None,
-        );
+    /// Waits until the controller has executed the command at `index`. Fails once the arm raises
+    /// an alarm that was not raised yet when the wait started, or once the queue is stopped or
+    /// cleared through this connection.
+    pub async fn wait_queued_command(&self, index: QueueIndex) -> Result<()> {
+        let alarms_state = self.get_alarms_state().await?;
+        let (tx, rx) = oneshot::channel::<Result<QueueIndex>>();
+        self.checking_queue_indices
+            .lock()
+            .await
+            .push((index, alarms_state, tx));
+        let _ = self.waiter_added.unbounded_send(());
+        match rx.await {
+            Ok(result) => result.map(|_| ()),
+            Err(_) => Err(DobotError::Cancelled),
+        }
+    }
 
-        let status = self.send_command_message_and_wait_execution(&mes).await;
+    /// Like `wait_queued_command`, but fails with `DobotError::Timeout` once `timeout` has passed.
+    pub async fn wait_queued_command_timeout(
+        &self,
+        index: QueueIndex,
+        timeout: Duration,
+    ) -> Result<()> {
+        self.with_timeout(timeout, self.wait_queued_command(index))
+            .await
+    }
 
-        match status {
-            CommunicateStatus::NoError(message) => Ok(QueueIndex(u64::from_params(
-                message.params_len as usize,
-                message.params,
-            ))),
-            _ => Err(DobotError::CommunicationError(status)),
-        }
+    pub async fn wait_queued_commands(&self, indices: &[QueueIndex]) -> Result<()> {
+        join_all(indices.iter().map(|x| self.wait_queued_command(*x)))
+            .await
+            .into_iter()
+            .collect()
     }
 
+    pub async fn wait_queued_commands_timeout(
+        &self,
+        indices: &[QueueIndex],
+        timeout: Duration,
+    ) -> Result<()> {
+        self.with_timeout(timeout, self.wait_queued_commands(indices))
+            .await
+    }
+
+    async fn with_timeout(
+        &self,
+        duration: Duration,
+        future: impl Future<Output = Result<()>>,
+    ) -> Result<()> {
+        timeout(&*self.runtime, duration, future)
+            .await
+            .unwrap_or(Err(DobotError::Timeout))
+    }
+
+    pub async fn get_queue_index(&self) -> Result<QueueIndex> {
+        self.execute::<GetQueuedCmdCurrentIndex>(()).await
+    }
+
+    /// Lists the serial ports a Dobot is likely attached to. Empty when the ports cannot be
+    /// listed.
     pub fn search_dobot() -> Vec<String> {
-        let ports = serialport::available_ports().unwrap();
+        let ports = serialport::available_ports().unwrap_or_default();
 
         ports
             .iter()
@@ -140,166 +317,1095 @@ impl Dobot {
             .collect()
     }
 
-    pub fn connect_dobot(
-        port_name: Option<&str>,
-        boudrate: Option<u32>,
-        fw_type: Option<&str>,
-        version: Option<&str>,
-    ) -> Result<Self> {
-        let boudrate = boudrate.unwrap_or(115200);
-        let port_name = port_name.map(|x| x.to_string()).unwrap_or(
-            Self::search_dobot()
-                .first()
-                .ok_or(DobotError::PortNotFound)?
-                .clone(),
+    pub fn builder() -> DobotBuilder {
+        DobotBuilder::new()
+    }
+
+    fn from_transport(
+        transport: Box<dyn Transport>,
+        retry_policy: RetryPolicy,
+        runtime: Arc<dyn Runtime>,
+        recording_error: RecordingError,
+    ) -> Self {
+        let (communicator, handle) = Communicator::new(transport, retry_policy, runtime.clone());
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let (stopped_sender, stopped) = oneshot::channel();
+        let (waiter_added, waiter_added_receiver) = mpsc::unbounded();
+        let (subscriber_added, subscriber_added_receiver) = mpsc::unbounded();
+        let dobot = Self {
+            handle,
+            checking_queue_indices: Arc::new(Mutex::new(vec![])),
+            waiter_added,
+            state_subscribers: Arc::new(StdMutex::new(vec![])),
+            subscriber_added,
+            retry_policy,
+            runtime: runtime.clone(),
+            recording_error,
+            driver: None,
+        };
+
+        runtime.spawn(
+            dobot
+                .clone()
+                .drive(
+                    communicator,
+                    waiter_added_receiver,
+                    subscriber_added_receiver,
+                    shutdown_receiver,
+                    stopped_sender,
+                )
+                .boxed(),
         );
+        Self {
+            driver: Some(Arc::new(Driver {
+                shutdown: StdMutex::new(Some(shutdown)),
+                stopped: StdMutex::new(Some(stopped)),
+            })),
+            ..dobot
+        }
+    }
+
+    /// Stops the driver and closes the port. Commands still waiting for an ACK, and any issued
+    /// afterwards on a clone of this `Dobot`, fail with `DobotError::Disconnected`.
+    pub async fn disconnect_dobot(&self) {
+        if let Some(driver) = &self.driver {
+            driver.shutdown();
+            let stopped = driver.stopped.lock().unwrap().take();
+            if let Some(stopped) = stopped {
+                let _ = stopped.await;
+            }
+        }
+    }
+
+    /// Returns a `Dobot` sharing this connection whose commands are retried according to
+    /// `retry_policy`, e.g. `dobot.with_retry_policy(patient).get_pose()`.
+    pub fn with_retry_policy(&self, retry_policy: RetryPolicy) -> Dobot {
+        Dobot {
+            retry_policy,
+            ..self.clone()
+        }
+    }
+
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.handle.diagnostics()
+    }
+
+    /// The error that stopped `DobotBuilder::record` from writing the recording, if one did.
+    /// The connection carries on without it.
+    pub fn recording_error(&self) -> Option<std::io::Error> {
+        self.recording_error.get()
+    }
+
+    pub async fn get_device_sn(&self) -> Result<String> {
+        self.execute::<GetDeviceSN>(()).await
+    }
+
+    /// Names the arm, e.g. to tell several of them apart with `DobotBuilder::device_name`.
+    pub async fn set_device_name(&self, name: &str) -> Result<()> {
+        self.execute::<SetDeviceName>(name.to_string()).await
+    }
+
+    pub async fn get_device_name(&self) -> Result<String> {
+        self.execute::<GetDeviceName>(()).await
+    }
+
+    pub async fn get_device_version(&self) -> Result<DeviceVersion> {
+        self.execute::<GetDeviceVersion>(()).await
+    }
+
+    /// Queues setting the end effector's offsets. Set them right away with
+    /// `execute::<SetEndEffectorParams>`.
+    pub fn set_end_effector_params(&self, end_effector_params: EndEffectorParams) -> QueuedCommand {
+        self.execute_queued::<SetEndEffectorParams>(end_effector_params)
+    }
+
+    /// Queues setting the velocity and acceleration ratios of point-to-point moves. Set them
+    /// right away with `execute::<SetPTPCommonParams>`.
+    #[allow(non_snake_case)]
+    pub fn set_PTP_common_params(&self, params: PTPCommonParams) -> QueuedCommand {
+        self.execute_queued::<SetPTPCommonParams>(params)
+    }
+
+    /// Queues a move to `ptp_cmd`, the same as `move_to`.
+    #[deprecated(note = "use `move_to`")]
+    pub fn set_ptp_cmd(&self, ptp_cmd: PTPCmd) -> QueuedCommand {
+        self.execute_queued::<SetPTPCmd>(ptp_cmd)
+    }
+
+    /// Queues a move to `ptp_cmd`, e.g. `dobot.move_to(ptp_cmd).await_done().await?`.
+    pub fn move_to(&self, ptp_cmd: PTPCmd) -> QueuedCommand {
+        self.execute_queued::<SetPTPCmd>(ptp_cmd)
+    }
+
+    /// Queues switching the suction cap to `suctions_cap_state`.
+    pub fn suction_cap(&self, suctions_cap_state: EndEffectorSuctionCapState) -> QueuedCommand {
+        self.execute_queued::<SetEndEffectorSuctionCup>(suctions_cap_state.into())
+    }
 
-        Ok(Self {
-            communicator: Arc::new(RwLock::new(Communicator::new(
-                Connector::connect(port_name.as_str(), boudrate, fw_type, version)
-                    .map_err(|e| DobotError::ConnectorError(e))?,
-                None,
-            ))),
-            checking_queue_indices: Arc::new(RwLock::new(vec![])),
+    /// Queues a lost step check. Completing it fails with `DobotError::LostStep` if the arm lost
+    /// steps during the moves before it.
+    pub fn detect_lost_step(&self) -> QueuedCommand {
+        self.execute_queued::<SetLostStepCmd>(())
+    }
+
+    pub async fn set_queued_cmd_start_exec(&self) -> Result<()> {
+        self.execute::<SetQueuedCmdStartExec>(()).await
+    }
+
+    /// Stops the queue once the current command is done. Commands still waited for fail with
+    /// `DobotError::QueueStopped`.
+    pub async fn set_queued_cmd_stop_exec(&self) -> Result<()> {
+        self.stop_queue::<SetQueuedCmdStopExec>(|| DobotError::QueueStopped)
+            .await
+    }
+
+    /// Stops the queue right away, aborting the current command.
+    pub async fn set_queued_cmd_force_stop_exec(&self) -> Result<()> {
+        self.stop_queue::<SetQueuedCmdForceStopExec>(|| DobotError::QueueStopped)
+            .await
+    }
+
+    /// Drops every command left in the queue. Commands still waited for fail with
+    /// `DobotError::QueueCleared`.
+    pub async fn set_queued_cmd_clear(&self) -> Result<()> {
+        self.stop_queue::<SetQueuedCmdClear>(|| DobotError::QueueCleared)
+            .await
+    }
+
+    async fn stop_queue<C: Command<Params = (), Response = ()>>(
+        &self,
+        error: impl Fn() -> DobotError,
+    ) -> Result<()> {
+        self.execute::<C>(()).await?;
+        self.fail_queue_waiters(error).await;
+        Ok(())
+    }
+
+    /// Queues switching the suction cap, the same as `suction_cap`. Switch it right away with
+    /// `execute::<SetEndEffectorSuctionCup>`.
+    #[deprecated(note = "use `suction_cap`")]
+    pub fn set_end_effector_suctions_cap(
+        &self,
+        suctions_cap_state: EndEffectorSuctionCapState,
+    ) -> QueuedCommand {
+        self.execute_queued::<SetEndEffectorSuctionCup>(suctions_cap_state.into())
+    }
+
+    pub async fn get_end_effector_suctions_cap(&self) -> Result<EndEffectorSuctionCapState> {
+        self.execute::<GetEndEffectorSuctionCup>(())
+            .await
+            .map(Into::into)
+    }
+
+    /// Whether the EIO input at `address` is high.
+    pub async fn get_io_di(&self, address: u8) -> Result<bool> {
+        Ok(self.execute::<GetIODI>(address).await?.level)
+    }
+
+    pub async fn set_hht_trig_mode(&self, mode: HHTTrigMode) -> Result<()> {
+        self.execute::<SetHHTTrigMode>(mode).await
+    }
+
+    pub async fn set_hht_trig_output_enabled(&self, is_enabled: bool) -> Result<()> {
+        self.execute::<SetHHTTrigOutputEnabled>(is_enabled).await
+    }
+
+    pub async fn get_hht_trig_output(&self) -> Result<bool> {
+        self.execute::<GetHHTTrigOutput>(()).await
+    }
+
+    /// Polls the hand-hold-teach trigger every `poll_interval` and yields an item each time the
+    /// button has been triggered. Failed polls are yielded too, and polling goes on after them;
+    /// the stream ends once the `Dobot` is disconnected.
+    pub fn hht_trig_events(&self, poll_interval: Duration) -> impl Stream<Item = Result<()>> + '_ {
+        stream::unfold((self, false), move |(dobot, yielded)| async move {
+            if yielded {
+                dobot.runtime.delay(poll_interval).await;
+            }
+            loop {
+                match dobot.get_hht_trig_output().await {
+                    Ok(true) => return Some((Ok(()), (dobot, true))),
+                    Ok(false) => dobot.runtime.delay(poll_interval).await,
+                    Err(DobotError::Disconnected) | Err(DobotError::Cancelled) => return None,
+                    Err(e) => return Some((Err(e), (dobot, true))),
+                }
+            }
         })
     }
 
-    pub fn disconnect_dobot(&self) {}
+    pub async fn get_alarms_state(&self) -> Result<AlarmsState> {
+        self.execute::<GetAlarmsState>(()).await
+    }
+
+    pub async fn clear_all_alarms_state(&self) -> Result<()> {
+        self.execute::<ClearAllAlarmsState>(()).await
+    }
+
+    pub async fn set_lost_step_params(&self, threshold: f32) -> Result<()> {
+        self.execute::<SetLostStepParams>(threshold).await
+    }
 
-    pub async fn set_end_effector_params(
+    /// Queues a lost step check, the same as `detect_lost_step`.
+    #[deprecated(note = "use `detect_lost_step`")]
+    pub fn set_lost_step_cmd(&self) -> QueuedCommand {
+        self.execute_queued::<SetLostStepCmd>(())
+    }
+
+    pub async fn reset_pose(
         &self,
-        end_effector_params: EndEffectorParams,
-        is_queued: bool,
-    ) -> ResultQueueIndex {
-        let mes = Message::new(
-            ProtocolID::ProtocolEndEffectorParams,
-            ReadWrite::Write,
-            is_queued,
-            &Some(end_effector_params),
-        );
+        manual: bool,
+        rear_arm_angle: f32,
+        front_arm_angle: f32,
+    ) -> Result<()> {
+        self.execute::<ResetPose>(ResetPoseParams {
+            manual,
+            rear_arm_angle,
+            front_arm_angle,
+        })
+        .await
+    }
 
-        self.send_command_message(&mes).await
+    /// Reads the velocity and acceleration of the controller's kinematic model. The link lengths
+    /// of the arm are not part of the reply, as the firmware does not report them.
+    pub async fn get_kinematics(&self) -> Result<Kinematics> {
+        self.execute::<GetKinematics>(()).await
     }
 
-    pub async fn set_PTP_common_params(
+    pub async fn set_angle_sensor_static_error(
         &self,
-        params: PTPCommonParams,
-        is_queued: bool,
-    ) -> ResultQueueIndex {
-        let mes = Message::new(
-            ProtocolID::ProtocolPTPCommonParams,
-            ReadWrite::Write,
-            is_queued,
-            &Some(params),
+        static_error: AngleSensorStaticError,
+    ) -> Result<()> {
+        self.execute::<SetAngleSensorStaticError>(static_error)
+            .await
+    }
+
+    pub async fn get_angle_sensor_static_error(&self) -> Result<AngleSensorStaticError> {
+        self.execute::<GetAngleSensorStaticError>(()).await
+    }
+
+    pub async fn set_angle_sensor_coef(&self, coef: AngleSensorCoef) -> Result<()> {
+        self.execute::<SetAngleSensorCoef>(coef).await
+    }
+
+    pub async fn get_angle_sensor_coef(&self) -> Result<AngleSensorCoef> {
+        self.execute::<GetAngleSensorCoef>(()).await
+    }
+
+    pub async fn set_base_decoder_static_error(&self, static_error: f32) -> Result<()> {
+        self.execute::<SetBaseDecoderStaticError>(static_error)
+            .await
+    }
+
+    pub async fn get_base_decoder_static_error(&self) -> Result<f32> {
+        self.execute::<GetBaseDecoderStaticError>(()).await
+    }
+
+    pub async fn backup_calibration_params(&self) -> Result<CalibrationParams> {
+        Ok(CalibrationParams {
+            angle_sensor_static_error: self.get_angle_sensor_static_error().await?,
+            angle_sensor_coef: self.get_angle_sensor_coef().await?,
+            base_decoder_static_error: self.get_base_decoder_static_error().await?,
+        })
+    }
+
+    pub async fn restore_calibration_params(&self, params: &CalibrationParams) -> Result<()> {
+        self.set_angle_sensor_static_error(params.angle_sensor_static_error)
+            .await?;
+        self.set_angle_sensor_coef(params.angle_sensor_coef).await?;
+        self.set_base_decoder_static_error(params.base_decoder_static_error)
+            .await
+    }
+
+    pub async fn get_firmware_mode(&self) -> Result<FirmwareMode> {
+        self.execute::<GetFirmwareMode>(()).await
+    }
+
+    /// Switches the controller to another firmware and waits up to `reconnect_timeout` for the
+    /// serial port to come back after the reboot. Fails the queued commands still waited for, as
+    /// the reboot drops the queue, and reads the firmware mode back where `switch` has one.
+    ///
+    /// The controller may reboot before its ACK makes it out, so a timed out switch is only
+    /// taken as done where `switch` has a mode and the controller reports it after the reboot.
+    /// Switching to a driver has no mode to confirm and fails on a timeout.
+    pub async fn set_firmware_switch(
+        &self,
+        switch: FirmwareSwitch,
+        reconnect_timeout: Duration,
+    ) -> Result<()> {
+        match self.execute::<SetFirmwareSwitch>(switch).await {
+            Ok(()) => {}
+            Err(DobotError::CommunicationError(CommunicateStatus::Timeout))
+                if switch.mode().is_some() => {}
+            Err(error) => return Err(error),
+        }
+        self.fail_queue_waiters(|| DobotError::Disconnected).await;
+
+        self.handle
+            .reconnect(reconnect_timeout)
+            .await
+            .map_err(|_| DobotError::Disconnected)?
+            .map_err(DobotError::ConnectorError)?;
+
+        // A timed out switch may never have reached the controller.
+        match switch.mode() {
+            Some(expected) => match self.get_firmware_mode().await? {
+                mode if mode == expected => Ok(()),
+                mode => Err(DobotError::FirmwareNotSwitched(mode)),
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub async fn get_uart4_peripherals_model(&self) -> Result<UART4PeripheralsModel> {
+        self.execute::<GetUART4PeripheralsModel>(()).await
+    }
+
+    pub async fn set_uart4_peripherals_enabled(&self, is_enabled: bool) -> Result<()> {
+        self.execute::<SetUART4PeripheralsEnabled>(is_enabled).await
+    }
+
+    pub async fn get_uart4_peripherals_enabled(&self) -> Result<bool> {
+        self.execute::<GetUART4PeripheralsEnabled>(()).await
+    }
+
+    pub async fn set_pulse_mode_enabled(&self, is_enabled: bool) -> Result<()> {
+        self.execute::<SetPulseModeEnabled>(is_enabled).await
+    }
+
+    pub async fn get_pulse_mode_enabled(&self) -> Result<bool> {
+        self.execute::<GetPulseModeEnabled>(()).await
+    }
+
+    pub async fn get_ptp_time(&self, ptp_cmd: PTPCmd) -> Result<Duration> {
+        self.execute::<GetPTPTime>(ptp_cmd)
+            .await
+            .map(|ms| Duration::from_millis(ms as u64))
+    }
+
+    #[cfg(feature = "servo-tuning")]
+    pub async fn set_servo_pid_params(&self, params: ServoPIDParams) -> Result<()> {
+        self.execute::<SetServoPIDParams>(params).await
+    }
+
+    #[cfg(feature = "servo-tuning")]
+    pub async fn get_servo_pid_params(
+        &self,
+        control_loop: ServoControlLoop,
+    ) -> Result<ServoPIDParams> {
+        self.execute::<GetServoPIDParams>(control_loop).await
+    }
+
+    #[cfg(feature = "servo-tuning")]
+    pub async fn set_servo_control_loop(&self, control_loop: ServoControlLoop) -> Result<()> {
+        self.execute::<SetServoControlLoop>(control_loop).await
+    }
+
+    #[cfg(feature = "servo-tuning")]
+    pub async fn save_servo_pid_params(&self, control_loop: ServoControlLoop) -> Result<()> {
+        self.execute::<SaveServoPIDParams>(control_loop).await
+    }
+
+    pub async fn get_pose(&self) -> Result<Pose> {
+        self.execute::<GetPose>(()).await
+    }
+
+    async fn send_command_message_and_wait_execution(
+        &self,
+        message: &Message,
+    ) -> CommunicateStatus {
+        let status_recv = self.handle.insert_message(message, self.retry_policy);
+        status_recv.await.unwrap_or(CommunicateStatus::Disconnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::types::FirmwareMode;
+    use crate::emulator::{Emulator, Fault};
+    use crate::protocol::descriptor::Violation;
+    use crate::protocol::message::ReadWrite;
+    use crate::protocol::protocol_id::ProtocolID;
+    use futures::future::join;
+    use tokio::time::delay_for;
+
+    const WAIT_TIME: Duration = Duration::from_millis(50);
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            timeout: WAIT_TIME,
+            ..Default::default()
+        }
+    }
+
+    fn runtime() -> Arc<dyn Runtime> {
+        crate::runtime::default_runtime().unwrap()
+    }
+
+    fn connect(transport: Box<dyn Transport>, retry_policy: RetryPolicy) -> Dobot {
+        Dobot::from_transport(
+            transport,
+            retry_policy,
+            runtime(),
+            RecordingError::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn write_error_is_propagated() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        emulator.inject(ProtocolID::ProtocolGetPose, Fault::WriteError);
+
+        let pose = dobot.get_pose().await;
+        assert!(matches!(pose, Err(DobotError::Io(_))));
+        assert!(dobot.get_pose().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn read_error_is_propagated() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        emulator.inject(ProtocolID::ProtocolGetPose, Fault::ReadError);
+
+        let pose = dobot.get_pose().await;
+        assert!(matches!(pose, Err(DobotError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn read_error_fails_queue_waiters() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+
+        let (result, ()) = join(dobot.wait_queued_command(QueueIndex(100)), async {
+            delay_for(WAIT_TIME).await;
+            emulator.inject(ProtocolID::ProtocolQueuedCmdCurrentIndex, Fault::ReadError);
+        })
+        .await;
+        assert!(matches!(result, Err(DobotError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn dobot_can_be_shared_between_tasks() {
+        let dobot = Arc::new(connect(Box::new(Emulator::new()), retry_policy()));
+
+        let tasks = (0..4).map(|_| {
+            let dobot = dobot.clone();
+            tokio::spawn(async move { dobot.get_pose().await.is_ok() })
+        });
+        for task in join_all(tasks).await {
+            assert!(task.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect_stops_the_driver() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        let waiter = dobot.clone();
+        let waiting =
+            tokio::spawn(async move { waiter.wait_queued_command(QueueIndex(100)).await });
+        delay_for(WAIT_TIME).await;
+
+        dobot.disconnect_dobot().await;
+        assert!(matches!(
+            waiting.await.unwrap(),
+            Err(DobotError::Disconnected)
+        ));
+        assert!(matches!(
+            dobot.get_pose().await,
+            Err(DobotError::Disconnected)
+        ));
+        assert!(!emulator.is_connected());
+    }
+
+    #[tokio::test]
+    async fn hht_trig_events_yield_errors_and_end_on_disconnect() {
+        let emulator = Emulator::new();
+        let dobot = connect(
+            Box::new(emulator.clone()),
+            RetryPolicy {
+                max_attempts: 1,
+                ..retry_policy()
+            },
         );
-        self.send_command_message(&mes).await
+        emulator.inject(ProtocolID::ProtocolHHTTrigOutput, Fault::DropRequest);
+
+        let events = dobot.hht_trig_events(WAIT_TIME);
+        pin_mut!(events);
+        assert!(matches!(
+            events.next().await,
+            Some(Err(DobotError::CommunicationError(
+                CommunicateStatus::Timeout
+            )))
+        ));
+
+        let (next, _) = futures::join!(events.next(), async {
+            delay_for(WAIT_TIME).await;
+            dobot.disconnect_dobot().await;
+        });
+        assert!(next.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_last_clone_stops_the_driver() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        let clone = dobot.with_retry_policy(retry_policy());
+        drop(dobot);
+        assert!(clone.get_pose().await.is_ok());
+
+        drop(clone);
+        delay_for(WAIT_TIME).await;
+        assert!(!emulator.is_connected());
     }
 
-    pub async fn set_ptp_cmd(&self, ptp_cmd: PTPCmd, is_queued: bool) -> ResultQueueIndex {
+    #[tokio::test]
+    async fn dropped_communicator_is_disconnected() {
+        let dobot = connect(Box::new(Emulator::new()), retry_policy());
+        let (communicator, handle) =
+            Communicator::new(Box::new(Emulator::new()), retry_policy(), runtime());
+        drop(communicator);
+        let dobot = Dobot { handle, ..dobot };
+
+        assert!(matches!(
+            dobot.get_pose().await,
+            Err(DobotError::Disconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reconnect_cancels_unanswered_commands() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        emulator.inject(ProtocolID::ProtocolGetPose, Fault::DropReply);
+
+        let (pose, reconnected) = join(dobot.get_pose(), async {
+            delay_for(WAIT_TIME / 2).await;
+            dobot.handle.reconnect(WAIT_TIME).await
+        })
+        .await;
+        assert!(matches!(pose, Err(DobotError::Cancelled)));
+        assert!(matches!(reconnected, Ok(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn late_reply_is_not_taken_for_the_retried_command() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        emulator.inject(ProtocolID::ProtocolGetPose, Fault::LateReply);
+
+        let pose = dobot.get_pose().await.unwrap();
+        assert_eq!(pose.x, 2.0);
+        let pose = dobot.get_pose().await.unwrap();
+        assert_eq!(pose.x, 3.0);
+        assert_eq!(
+            dobot.diagnostics(),
+            Diagnostics {
+                stale_replies: 1,
+                timeouts: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_command_with_lost_reply_is_not_resent() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::DropReply);
+
+        let index = dobot.move_to(PTPCmd::default()).accepted().await;
+        assert_eq!(index.unwrap(), QueueIndex(2));
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 2);
+    }
+
+    #[tokio::test]
+    async fn first_queued_command_with_lost_reply_times_out() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::DropReply);
+
+        let index = dobot.move_to(PTPCmd::default()).accepted().await;
+        assert!(matches!(
+            index,
+            Err(DobotError::CommunicationError(CommunicateStatus::Timeout))
+        ));
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 1);
+    }
+
+    #[tokio::test]
+    async fn illegal_command_is_not_sent() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
         let mes = Message::new(
             ProtocolID::ProtocolPTPCmd,
             ReadWrite::Write,
-            is_queued,
-            &Some(ptp_cmd),
+            true,
+            &Some(PTPCommonParams::default()),
         );
-        self.send_command_message(&mes).await
+
+        let status = dobot.send_command_message_and_wait_execution(&mes).await;
+        assert!(matches!(
+            status,
+            CommunicateStatus::Illegal(Violation::ParamsSize {
+                expected: 17,
+                actual: 8,
+                ..
+            })
+        ));
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 0);
+        assert!(dobot.get_pose().await.is_ok());
     }
 
-    pub async fn set_queued_cmd_start_exec(&self) -> Result<()> {
-        let mes = Message::new::<()>(
-            ProtocolID::ProtocolQueuedCmdStartExec,
-            ReadWrite::Write,
-            false,
-            &None,
+    #[tokio::test]
+    async fn queued_command_with_lost_request_is_resent() {
+        let emulator = Emulator::new();
+        let dobot = connect(
+            Box::new(emulator.clone()),
+            RetryPolicy {
+                retry_queued: true,
+                ..retry_policy()
+            },
         );
+        dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::DropRequest);
 
-        let status_recv = { self.communicator.write().await.insert_message(&mes) };
-        let status = status_recv.await.unwrap();
-        match status {
-            CommunicateStatus::NoError(_) => Ok(()),
-            _ => Err(DobotError::CommunicationError(status)),
-        }
+        let index = dobot.move_to(PTPCmd::default()).accepted().await;
+        assert_eq!(index.unwrap(), QueueIndex(2));
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 2);
     }
 
-    pub async fn set_end_effector_suctions_cap(
-        &self,
-        suctions_cap_state: EndEffectorSuctionCapState,
-        is_queued: bool,
-    ) -> ResultQueueIndex {
-        let mes = Message::new::<EndEffectorSuctionCapParams>(
-            ProtocolID::ProtocolEndEffectorSuctionCup,
-            ReadWrite::Write,
-            is_queued,
-            &Some(suctions_cap_state.into()),
-        );
-        self.send_command_message(&mes).await
+    #[tokio::test]
+    async fn queued_commands_are_not_resent_by_default() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::DropRequest);
+
+        let index = dobot.move_to(PTPCmd::default()).accepted().await;
+        assert!(matches!(
+            index,
+            Err(DobotError::CommunicationError(CommunicateStatus::Timeout))
+        ));
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 1);
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolWAITCmd), 0);
     }
 
-    pub async fn set_hht_trig_mode(&self, mode: HHTTrigMode) -> Result<()> {
+    #[tokio::test]
+    async fn lost_reply_behind_a_held_queue_is_not_resent_by_default() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+        emulator.hold_queue();
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::DropReply);
+
+        let index = dobot.move_to(PTPCmd::default()).accepted().await;
+        assert!(matches!(
+            index,
+            Err(DobotError::CommunicationError(CommunicateStatus::Timeout))
+        ));
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 2);
+    }
+
+    #[tokio::test]
+    async fn lost_reply_is_settled_by_the_next_queued_ack() {
+        let emulator = Emulator::new();
+        // Driven by hand so that both commands are sure to be in flight at once.
+        let (mut communicator, handle) =
+            Communicator::new(Box::new(emulator.clone()), retry_policy(), runtime());
+
         let mes = Message::new(
-            ProtocolID::ProtocolHHTTrigMode,
+            ProtocolID::ProtocolPTPCmd,
             ReadWrite::Write,
-            false,
-            &Some(mode),
+            true,
+            &Some(PTPCmd::default()),
         );
+        let first = handle.insert_message(&mes, retry_policy());
+        while communicator.run().await && emulator.enqueued(ProtocolID::ProtocolPTPCmd) == 0 {}
+        assert!(matches!(first.await, Ok(CommunicateStatus::NoError(_))));
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::DropReply);
 
-        let status = self.send_command_message_and_wait_execution(&mes).await;
+        let acks = join(
+            handle.insert_message(&mes, retry_policy()),
+            handle.insert_message(&mes, retry_policy()),
+        )
+        .fuse();
+        let run = async { while communicator.run().await {} }.fuse();
+        pin_mut!(acks, run);
+        let queue_index = |ack| match ack {
+            Ok(CommunicateStatus::NoError(message)) => message.queue_index(),
+            ack => panic!("unexpected {:?}", ack),
+        };
 
-        match status {
-            CommunicateStatus::NoError(_) => Ok(()),
-            _ => Err(DobotError::CommunicationError(status)),
-        }
+        let (first, second) = select! {
+            acks = acks => acks,
+            () = run => unreachable!(),
+        };
+        assert_eq!(queue_index(first), 2);
+        assert_eq!(queue_index(second), 3);
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 3);
     }
 
-    pub async fn set_hht_trig_output_enabled(&self, is_enabled: bool) -> Result<()> {
-        let mes = Message::new(
-            ProtocolID::ProtocolHHTTrigOutputEnabled,
-            ReadWrite::Write,
-            false,
-            &Some(is_enabled),
+    #[tokio::test]
+    async fn retry_policy_can_be_overridden_per_call() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        emulator.inject(ProtocolID::ProtocolGetPose, Fault::DropReply);
+        let once = dobot.with_retry_policy(RetryPolicy {
+            max_attempts: 1,
+            ..retry_policy()
+        });
+
+        assert!(matches!(
+            once.get_pose().await,
+            Err(DobotError::CommunicationError(CommunicateStatus::Timeout))
+        ));
+
+        dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::DropRequest);
+        let retry_queued = dobot.with_retry_policy(RetryPolicy {
+            retry_queued: true,
+            ..retry_policy()
+        });
+        let index = retry_queued.move_to(PTPCmd::default()).accepted().await;
+        assert_eq!(index.unwrap(), QueueIndex(2));
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 2);
+    }
+
+    #[tokio::test]
+    async fn too_long_device_name_is_rejected() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+
+        let name = "x".repeat(PARAMS_SIZE + 1);
+        let result = dobot.set_device_name(&name).await;
+        assert!(
+            matches!(result, Err(DobotError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput)
         );
+        assert_ne!(dobot.get_device_name().await.unwrap(), name);
+    }
 
-        let status = self.send_command_message_and_wait_execution(&mes).await;
+    #[tokio::test]
+    async fn builder_picks_the_device_by_name_and_serial_number() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        dobot.set_device_name("left").await.unwrap();
 
-        match status {
-            CommunicateStatus::NoError(_) => Ok(()),
-            _ => Err(DobotError::CommunicationError(status)),
-        }
+        let dobot = Dobot::builder()
+            .transport(Box::new(emulator.clone()))
+            .serial_number(crate::emulator::SERIAL_NUMBER)
+            .device_name("left")
+            .firmware(FirmwareMode::Dobot)
+            .ack_timeout(WAIT_TIME)
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(dobot.get_device_name().await.unwrap(), "left");
+
+        let other = Dobot::builder()
+            .transport(Box::new(emulator.clone()))
+            .device_name("right")
+            .connect()
+            .await;
+        assert!(matches!(other, Err(DobotError::PortNotFound)));
     }
 
-    pub async fn get_pose(&self) -> Result<Pose> {
+    #[tokio::test]
+    async fn builder_does_not_resend_queued_commands_by_default() {
+        let emulator = Emulator::new();
+        let dobot = Dobot::builder()
+            .transport(Box::new(emulator.clone()))
+            .ack_timeout(WAIT_TIME)
+            .connect()
+            .await
+            .unwrap();
+        dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::DropReply);
+
+        assert!(dobot.move_to(PTPCmd::default()).accepted().await.is_ok());
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::DropRequest);
+        assert!(dobot.move_to(PTPCmd::default()).accepted().await.is_err());
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 2);
+    }
+
+    #[tokio::test]
+    async fn dropped_caller_does_not_stop_the_communicator() {
+        let dobot = connect(Box::new(Emulator::new()), retry_policy());
+        drop(dobot.get_pose().boxed());
         let mes = Message::new::<()>(ProtocolID::ProtocolGetPose, ReadWrite::Read, false, &None);
+        drop(dobot.handle.insert_message(&mes, retry_policy()));
 
-        let status = self.send_command_message_and_wait_execution(&mes).await;
+        assert!(dobot.get_pose().await.is_ok());
+    }
 
-        match status {
-            CommunicateStatus::NoError(message) => Ok(Pose::from_params(
-                message.params_len as usize,
-                message.params,
-            )),
-            _ => Err(DobotError::CommunicationError(status)),
-        }
+    #[tokio::test]
+    async fn timed_out_waiter_is_forgotten() {
+        let dobot = connect(Box::new(Emulator::new()), retry_policy());
+
+        assert!(matches!(
+            dobot
+                .wait_queued_command_timeout(QueueIndex(100), WAIT_TIME)
+                .await,
+            Err(DobotError::Timeout)
+        ));
+        delay_for(WAIT_TIME).await;
+        assert!(dobot.checking_queue_indices.lock().await.is_empty());
     }
 
-    async fn send_command_message(&self, message: &Message) -> ResultQueueIndex {
-        let status_recv = { self.communicator.write().await.insert_message(message) };
-        let status = status_recv.await.unwrap();
-        if let CommunicateStatus::NoError(ack_mes) = status {
-            if message.is_queued != 0 {
-                Ok(Some(QueueIndex(ack_mes.params[0] as u64)))
-            } else {
-                Ok(None)
-            }
-        } else {
-            Err(CommunicationError(status))
+    #[tokio::test]
+    async fn alarm_fails_queue_waiters() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        let index = dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+
+        let (waited, ()) = join(dobot.wait_queued_command(QueueIndex(100)), async {
+            delay_for(WAIT_TIME).await;
+            emulator.alarm(0x00);
+        })
+        .await;
+        assert!(matches!(
+            waited,
+            Err(DobotError::Alarm(alarms_state)) if alarms_state.is_alarmed(0x00)
+        ));
+        dobot.clear_all_alarms_state().await.unwrap();
+        let (waited, ()) = join(dobot.wait_queued_command(QueueIndex(100)), async {
+            delay_for(WAIT_TIME).await;
+            emulator.alarm(0x50);
+        })
+        .await;
+        assert!(matches!(waited, Err(DobotError::LostStep(_))));
+        // Commands executed before the alarm still count as done.
+        assert!(dobot.wait_queued_command(index).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn firmware_switch_is_confirmed_and_fails_queue_waiters() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        emulator.hold_queue();
+        let index = dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+
+        let (waited, switched) = join(dobot.wait_queued_command(index), async {
+            delay_for(WAIT_TIME).await;
+            dobot
+                .set_firmware_switch(FirmwareSwitch::Printing, WAIT_TIME)
+                .await
+        })
+        .await;
+        assert!(matches!(waited, Err(DobotError::Disconnected)));
+        switched.unwrap();
+        assert_eq!(
+            dobot.get_firmware_mode().await.unwrap(),
+            FirmwareMode::Printing
+        );
+
+        emulator.inject(ProtocolID::ProtocolFirmwareSwitch, Fault::DropRequest);
+        let once = dobot.with_retry_policy(RetryPolicy {
+            max_attempts: 1,
+            ..retry_policy()
+        });
+        assert!(matches!(
+            once.set_firmware_switch(FirmwareSwitch::Dobot, WAIT_TIME)
+                .await,
+            Err(DobotError::FirmwareNotSwitched(FirmwareMode::Printing))
+        ));
+
+        emulator.inject(ProtocolID::ProtocolFirmwareSwitch, Fault::DropReply);
+        once.set_firmware_switch(FirmwareSwitch::Dobot, WAIT_TIME)
+            .await
+            .unwrap();
+
+        emulator.inject(ProtocolID::ProtocolFirmwareSwitch, Fault::DropReply);
+        assert!(matches!(
+            once.set_firmware_switch(FirmwareSwitch::Driver1, WAIT_TIME)
+                .await,
+            Err(DobotError::CommunicationError(CommunicateStatus::Timeout))
+        ));
+    }
+
+    #[tokio::test]
+    async fn alarm_raised_before_the_wait_does_not_fail_it() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        emulator.alarm(0x00);
+
+        let index = dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+        assert!(dobot.wait_queued_command(index).await.is_ok());
+        assert!(matches!(
+            dobot
+                .wait_queued_command_timeout(QueueIndex(100), WAIT_TIME * 2)
+                .await,
+            Err(DobotError::Timeout)
+        ));
+
+        let (waited, ()) = join(dobot.wait_queued_command(QueueIndex(100)), async {
+            delay_for(WAIT_TIME).await;
+            emulator.alarm(0x01);
+        })
+        .await;
+        assert!(matches!(
+            waited,
+            Err(DobotError::Alarm(alarms_state))
+                if alarms_state.is_alarmed(0x01) && !alarms_state.is_alarmed(0x00)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stopping_or_clearing_the_queue_fails_queue_waiters() {
+        let dobot = connect(Box::new(Emulator::new()), retry_policy());
+
+        let (waited, stopped) = join(dobot.wait_queued_command(QueueIndex(100)), async {
+            delay_for(WAIT_TIME).await;
+            dobot.set_queued_cmd_stop_exec().await
+        })
+        .await;
+        stopped.unwrap();
+        assert!(matches!(waited, Err(DobotError::QueueStopped)));
+
+        let (waited, cleared) = join(dobot.wait_queued_command(QueueIndex(100)), async {
+            delay_for(WAIT_TIME).await;
+            dobot.set_queued_cmd_clear().await
+        })
+        .await;
+        cleared.unwrap();
+        assert!(matches!(waited, Err(DobotError::QueueCleared)));
+    }
+
+    #[tokio::test]
+    async fn queue_indices_keep_counting_past_one_byte() {
+        let dobot = connect(Box::new(Emulator::new()), retry_policy());
+
+        let mut last = QueueIndex(0);
+        for _ in 0..3000 {
+            let index = dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+            assert_eq!(index, QueueIndex(last.0 + 1));
+            last = index;
         }
+        assert_eq!(dobot.get_queue_index().await.unwrap(), last);
+        dobot.wait_queued_command(last).await.unwrap();
+        assert!(matches!(
+            dobot
+                .wait_queued_command_timeout(QueueIndex(last.0 + 1), WAIT_TIME)
+                .await,
+            Err(DobotError::Timeout)
+        ));
     }
 
-    async fn send_command_message_and_wait_execution(
-        &self,
-        message: &Message,
-    ) -> CommunicateStatus {
-        let status_recv = { self.communicator.write().await.insert_message(message) };
-        status_recv.await.unwrap()
+    #[tokio::test]
+    async fn queued_command_resolves_once_accepted_and_completed() {
+        let dobot = connect(Box::new(Emulator::new()), retry_policy());
+
+        dobot.move_to(PTPCmd::default()).await_done().await.unwrap();
+        let mut moved = dobot.move_to(PTPCmd::default());
+        let index = moved.accepted().await.unwrap();
+        assert_eq!(moved.accepted().await.unwrap(), index);
+        assert_eq!(moved.completed().await.unwrap(), index);
+        assert_eq!(dobot.get_queue_index().await.unwrap(), index);
+    }
+
+    #[tokio::test]
+    async fn queued_command_is_not_resent_after_a_dropped_wait() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+
+        let mut moved = dobot.move_to(PTPCmd::default());
+        // Drops the future, most likely before the ACK is in.
+        let _ = moved.accepted().now_or_never();
+        assert_eq!(moved.accepted().await.unwrap(), QueueIndex(1));
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 1);
+    }
+
+    #[tokio::test]
+    async fn queued_commands_are_queued_in_the_order_they_were_created() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+
+        let mut first = dobot.move_to(PTPCmd::default());
+        let mut second = dobot.suction_cap(EndEffectorSuctionCapState::In);
+        let mut third = dobot.move_to(PTPCmd::default());
+        assert_eq!(third.accepted().await.unwrap(), QueueIndex(3));
+        assert_eq!(second.accepted().await.unwrap(), QueueIndex(2));
+        assert_eq!(first.accepted().await.unwrap(), QueueIndex(1));
+    }
+
+    #[tokio::test]
+    async fn queued_command_fails_in_either_phase() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::WriteError);
+        assert!(matches!(
+            dobot.move_to(PTPCmd::default()).await_done().await,
+            Err(DobotError::Io(_))
+        ));
+
+        emulator.hold_queue();
+        let mut grabbed = dobot.suction_cap(EndEffectorSuctionCapState::In);
+        grabbed.accepted().await.unwrap();
+        let (completed, ()) = join(grabbed.completed(), async {
+            delay_for(WAIT_TIME).await;
+            emulator.alarm(0x50);
+        })
+        .await;
+        assert!(matches!(completed, Err(DobotError::LostStep(_))));
+    }
+
+    #[tokio::test]
+    async fn lost_step_check_fails_when_it_raises_the_alarm_as_it_executes() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+
+        emulator.hold_queue();
+        let mut check = dobot.detect_lost_step();
+        check.accepted().await.unwrap();
+        let (completed, ()) = join(check.completed(), async {
+            delay_for(WAIT_TIME).await;
+            emulator.resume_queue_raising(0x50);
+        })
+        .await;
+        assert!(matches!(completed, Err(DobotError::LostStep(_))));
+    }
+
+    #[tokio::test]
+    async fn lost_step_check_fails_when_the_alarm_beat_the_wait() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+
+        let mut check = dobot.detect_lost_step();
+        check.accepted().await.unwrap();
+        emulator.alarm(0x51);
+        assert!(matches!(
+            check.completed().await,
+            Err(DobotError::LostStep(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn state_snapshots_are_shared_between_subscribers() {
+        let emulator = Emulator::new();
+        let dobot = connect(Box::new(emulator.clone()), retry_policy());
+        dobot
+            .execute::<SetEndEffectorSuctionCup>(EndEffectorSuctionCapState::In.into())
+            .await
+            .unwrap();
+        emulator.set_digital_input(3);
+
+        let mut dashboard = dobot.subscribe_state(WAIT_TIME);
+        let mut logger = dobot.subscribe_state_with_inputs(WAIT_TIME, &[2, 3]);
+        let (shown, logged): (Vec<_>, Vec<_>) = join(
+            (&mut dashboard).take(3).collect(),
+            (&mut logger).take(3).collect(),
+        )
+        .await;
+
+        assert_eq!(shown[0].suction_cap, EndEffectorSuctionCapState::In);
+        assert_eq!(logged[0].digital_inputs, vec![(2, false), (3, true)]);
+        // Both are served by the same polls once the second one has caught up.
+        assert_eq!(shown[2].pose.x, logged[2].pose.x);
+        assert!(shown[2].taken_at - shown[1].taken_at >= WAIT_TIME / 2);
+
+        dobot.disconnect_dobot().await;
+        assert!(dashboard.next().await.is_none());
     }
 }
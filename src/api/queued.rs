@@ -0,0 +1,82 @@
+use futures::channel::oneshot;
+
+use crate::api::{Dobot, DobotError, QueueIndex, Result};
+use crate::communicator::CommunicateStatus;
+use crate::protocol::message::Message;
+use crate::protocol::protocol_id::ProtocolID;
+
+/// A command for the controller's queue, e.g. from `Dobot::move_to`. It is sent as soon as it is
+/// created, so commands reach the queue in the order they were created in, whichever order they
+/// are awaited in.
+///
+/// `dobot.move_to(cmd).await_done().await?` moves and waits in one line. `accepted` lets the
+/// caller go on, e.g. to queue the next command, as soon as the controller has taken this one,
+/// and `completed` waits for it afterwards.
+///
+/// A future of `accepted` or `completed` that is dropped leaves the answer to be picked up by the
+/// next one.
+#[must_use = "the command is queued either way, await it to learn whether it was"]
+pub struct QueuedCommand {
+    dobot: Dobot,
+    state: State,
+    checks_lost_step: bool,
+}
+
+enum State {
+    Sent(oneshot::Receiver<CommunicateStatus>),
+    Accepted(QueueIndex),
+    Failed,
+}
+
+impl QueuedCommand {
+    pub(crate) fn new(dobot: &Dobot, message: Message) -> Self {
+        let receiver = dobot.handle.insert_message(&message, dobot.retry_policy);
+        Self {
+            dobot: dobot.clone(),
+            checks_lost_step: message.id == ProtocolID::ProtocolLostStepDetect as u8,
+            state: State::Sent(receiver),
+        }
+    }
+
+    /// Resolves to the command's queue index once the controller has put it in the queue. Once
+    /// that failed, it fails with `DobotError::Cancelled`.
+    pub async fn accepted(&mut self) -> Result<QueueIndex> {
+        let status = match &mut self.state {
+            State::Sent(receiver) => receiver.await.unwrap_or(CommunicateStatus::Disconnected),
+            State::Accepted(index) => return Ok(*index),
+            State::Failed => return Err(DobotError::Cancelled),
+        };
+        match status {
+            CommunicateStatus::NoError(message) => {
+                let index = QueueIndex(message.queue_index());
+                self.state = State::Accepted(index);
+                Ok(index)
+            }
+            _ => {
+                self.state = State::Failed;
+                Err(status.into())
+            }
+        }
+    }
+
+    /// Resolves once the controller has executed the command, failing as
+    /// `Dobot::wait_queued_command` does. A lost step check also fails with
+    /// `DobotError::LostStep` if a lost step alarm is raised once it has run.
+    pub async fn completed(&mut self) -> Result<QueueIndex> {
+        let index = self.accepted().await?;
+        self.dobot.wait_queued_command(index).await?;
+        if self.checks_lost_step {
+            // The check may have raised the alarm before the wait took its snapshot.
+            let alarms_state = self.dobot.get_alarms_state().await?;
+            if alarms_state.has_lost_step() {
+                return Err(DobotError::LostStep(alarms_state));
+            }
+        }
+        Ok(index)
+    }
+
+    /// Waits until the controller has executed the command, see `completed`.
+    pub async fn await_done(mut self) -> Result<()> {
+        self.completed().await.map(|_| ())
+    }
+}
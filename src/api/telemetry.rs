@@ -0,0 +1,150 @@
+use crate::api::types::{AlarmsState, EndEffectorSuctionCapState, Pose};
+use crate::api::{Dobot, QueueIndex, Result};
+use futures::channel::mpsc;
+use futures::future::{try_join_all, FutureExt};
+use futures::stream::{Stream, StreamExt};
+use futures::{pin_mut, select, try_join};
+use std::time::{Duration, Instant};
+
+/// The state of the arm at `taken_at`, see `Dobot::subscribe_state`.
+#[derive(Debug, Clone)]
+pub struct StateSnapshot {
+    pub taken_at: Instant,
+    /// The cartesian pose along with the joint angles.
+    pub pose: Pose,
+    /// The index of the last queued command the controller executed.
+    pub queue_index: QueueIndex,
+    pub alarms_state: AlarmsState,
+    pub suction_cap: EndEffectorSuctionCapState,
+    /// The level of every EIO input any subscriber asked for, by address.
+    pub digital_inputs: Vec<(u8, bool)>,
+}
+
+pub(crate) struct StateSubscriber {
+    interval: Duration,
+    digital_inputs: Vec<u8>,
+    next_due: Instant,
+    sender: mpsc::Sender<StateSnapshot>,
+}
+
+impl Dobot {
+    /// Yields a snapshot of the arm's state about every `interval` until the connection is
+    /// closed.
+    ///
+    /// The driver polls the arm once for every subscriber that is due, so subscribers with the
+    /// same interval share the serial traffic. A subscriber that falls behind skips snapshots
+    /// rather than queueing them up, and so does everyone on a failed poll, e.g. a timeout.
+    pub fn subscribe_state(&self, interval: Duration) -> impl Stream<Item = StateSnapshot> {
+        self.subscribe_state_with_inputs(interval, &[])
+    }
+
+    /// Like `subscribe_state`, with the levels of the EIO inputs at `digital_inputs` as well.
+    pub fn subscribe_state_with_inputs(
+        &self,
+        interval: Duration,
+        digital_inputs: &[u8],
+    ) -> impl Stream<Item = StateSnapshot> {
+        let (sender, receiver) = mpsc::channel(0);
+        self.state_subscribers
+            .lock()
+            .unwrap()
+            .push(StateSubscriber {
+                interval,
+                digital_inputs: digital_inputs.to_vec(),
+                next_due: Instant::now(),
+                sender,
+            });
+        let _ = self.subscriber_added.unbounded_send(());
+        receiver
+    }
+
+    pub(crate) async fn publish_state_loop(
+        &self,
+        mut subscriber_added: mpsc::UnboundedReceiver<()>,
+    ) {
+        loop {
+            let (next_due, digital_inputs) = {
+                let mut subscribers = self.state_subscribers.lock().unwrap();
+                subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+                let mut digital_inputs = subscribers
+                    .iter()
+                    .flat_map(|subscriber| subscriber.digital_inputs.iter().copied())
+                    .collect::<Vec<_>>();
+                digital_inputs.sort_unstable();
+                digital_inputs.dedup();
+                (
+                    subscribers
+                        .iter()
+                        .map(|subscriber| subscriber.next_due)
+                        .min(),
+                    digital_inputs,
+                )
+            };
+
+            let now = Instant::now();
+            match next_due {
+                None => {
+                    if subscriber_added.next().await.is_none() {
+                        return;
+                    }
+                    continue;
+                }
+                // A subscriber added in the meantime may be due earlier.
+                Some(next_due) if next_due > now => {
+                    let delay = self.runtime.delay(next_due - now).fuse();
+                    let added = subscriber_added.next().fuse();
+                    pin_mut!(delay, added);
+                    select! {
+                        () = delay => {},
+                        added = added => if added.is_none() {
+                            return;
+                        },
+                    }
+                    continue;
+                }
+                Some(_) => {}
+            }
+
+            let snapshot = self.get_state_snapshot(&digital_inputs).await;
+            for subscriber in self.state_subscribers.lock().unwrap().iter_mut() {
+                // Serving whoever is almost due as well lines subscribers up on the same polls.
+                if subscriber.next_due > now + subscriber.interval / 2 {
+                    continue;
+                }
+                if let Ok(snapshot) = &snapshot {
+                    let _ = subscriber.sender.try_send(snapshot.clone());
+                }
+                subscriber.next_due = now + subscriber.interval;
+            }
+        }
+    }
+
+    /// Ends every state stream.
+    pub(crate) fn close_state_subscriptions(&self) {
+        self.state_subscribers.lock().unwrap().clear();
+    }
+
+    async fn get_state_snapshot(&self, digital_inputs: &[u8]) -> Result<StateSnapshot> {
+        let taken_at = Instant::now();
+        let (pose, queue_index, alarms_state, suction_cap, levels) = try_join!(
+            self.get_pose(),
+            self.get_queue_index(),
+            self.get_alarms_state(),
+            self.get_end_effector_suctions_cap(),
+            try_join_all(
+                digital_inputs
+                    .iter()
+                    .map(|address| self.get_io_di(*address))
+            ),
+        )?;
+
+        Ok(StateSnapshot {
+            taken_at,
+            pose,
+            queue_index,
+            alarms_state,
+            suction_cap,
+            digital_inputs: digital_inputs.iter().copied().zip(levels).collect(),
+        })
+    }
+}
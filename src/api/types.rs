@@ -1,10 +1,12 @@
-use crate::protocol::message::ToParamable;
+use crate::protocol::message::{FromParamable, ToParamable};
 use crate::protocol::message::{FromParams, ToParams, PARAMS_SIZE};
 use byteorder::{LittleEndian, ReadBytesExt};
-use derives::ToParams;
+use derives::{FromParams, ToParams};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::io::{Cursor, Write};
 
-#[derive(Debug, Default, Copy, Clone, ToParams)]
+#[derive(Debug, Default, Copy, Clone, ToParams, FromParams)]
 pub struct PTPCmd {
     pub ptp_mode: u8,
     pub x: f32,
@@ -13,32 +15,32 @@ pub struct PTPCmd {
     pub r: f32,
 }
 
-#[derive(Debug, Default, Copy, Clone, ToParams)]
+#[derive(Debug, Default, Copy, Clone, ToParams, FromParams)]
 pub struct EndEffectorParams {
     pub x_bias: f32,
     pub y_bias: f32,
     pub z_bias: f32,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum EndEffectorSuctionCapState {
     Off,
     In,
     Out,
 }
 
-impl Into<EndEffectorSuctionCapParams> for EndEffectorSuctionCapState {
-    fn into(self) -> EndEffectorSuctionCapParams {
-        match self {
-            Self::Off => EndEffectorSuctionCapParams {
+impl From<EndEffectorSuctionCapState> for EndEffectorSuctionCapParams {
+    fn from(state: EndEffectorSuctionCapState) -> Self {
+        match state {
+            EndEffectorSuctionCapState::Off => EndEffectorSuctionCapParams {
                 enable_ctrl: false,
                 suck: false,
             },
-            Self::In => EndEffectorSuctionCapParams {
+            EndEffectorSuctionCapState::In => EndEffectorSuctionCapParams {
                 enable_ctrl: true,
                 suck: true,
             },
-            Self::Out => EndEffectorSuctionCapParams {
+            EndEffectorSuctionCapState::Out => EndEffectorSuctionCapParams {
                 enable_ctrl: true,
                 suck: false,
             },
@@ -46,13 +48,25 @@ impl Into<EndEffectorSuctionCapParams> for EndEffectorSuctionCapState {
     }
 }
 
-#[derive(Debug, Copy, Clone, ToParams)]
+impl From<EndEffectorSuctionCapParams> for EndEffectorSuctionCapState {
+    fn from(params: EndEffectorSuctionCapParams) -> Self {
+        match params {
+            EndEffectorSuctionCapParams {
+                enable_ctrl: false, ..
+            } => EndEffectorSuctionCapState::Off,
+            EndEffectorSuctionCapParams { suck: true, .. } => EndEffectorSuctionCapState::In,
+            EndEffectorSuctionCapParams { suck: false, .. } => EndEffectorSuctionCapState::Out,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ToParams, FromParams)]
 pub struct EndEffectorSuctionCapParams {
     pub enable_ctrl: bool,
     pub suck: bool,
 }
 
-#[derive(Debug, Default, Copy, Clone, ToParams)]
+#[derive(Debug, Default, Copy, Clone, ToParams, FromParams)]
 pub struct PTPCommonParams {
     pub velocity_ratio: f32,
     pub acceleration_ratio: f32,
@@ -76,6 +90,164 @@ impl ToParams for HHTTrigMode {
     }
 }
 
+/// The level of the EIO input at `address`, as the controller replies to an IODI read.
+#[derive(Debug, Default, Copy, Clone, PartialEq, FromParams)]
+pub struct IODIParams {
+    pub address: u8,
+    pub level: bool,
+}
+
+const ALARMS_STATE_SIZE: usize = 16;
+// ALARM_LOSE_STEP_AXIS1 to ALARM_LOSE_STEP_AXIS4 in the alarm table of the Dobot Magician
+// user guide, one per joint.
+const LOST_STEP_ALARMS: [u8; 4] = [0x50, 0x51, 0x52, 0x53];
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct AlarmsState {
+    pub bits: [u8; ALARMS_STATE_SIZE],
+}
+
+impl AlarmsState {
+    pub fn is_alarmed(&self, alarm: u8) -> bool {
+        self.bits[(alarm / 8) as usize] & (1 << (alarm % 8)) != 0
+    }
+
+    pub fn any(&self) -> bool {
+        self.bits.iter().any(|&b| b != 0)
+    }
+
+    pub fn has_lost_step(&self) -> bool {
+        LOST_STEP_ALARMS.iter().any(|&alarm| self.is_alarmed(alarm))
+    }
+
+    /// The alarms raised now that were not raised in `earlier`.
+    pub fn raised_since(&self, earlier: &AlarmsState) -> AlarmsState {
+        let mut bits = self.bits;
+        for (bit, earlier) in bits.iter_mut().zip(earlier.bits.iter()) {
+            *bit &= !earlier;
+        }
+        Self { bits }
+    }
+}
+
+impl FromParams for AlarmsState {
+    fn from_params(_size: usize, params: [u8; PARAMS_SIZE]) -> Self {
+        let mut bits = [0u8; ALARMS_STATE_SIZE];
+        bits.copy_from_slice(&params[..ALARMS_STATE_SIZE]);
+
+        Self { bits }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, ToParams, FromParams)]
+pub struct ResetPoseParams {
+    pub manual: bool,
+    pub rear_arm_angle: f32,
+    pub front_arm_angle: f32,
+}
+
+/// Velocity and acceleration limits of the controller's kinematic model. The firmware does not
+/// report the arm's link lengths.
+#[derive(Debug, Default, Copy, Clone, PartialEq, FromParams)]
+pub struct Kinematics {
+    pub velocity: f32,
+    pub acceleration: f32,
+}
+
+// The UART4PeripheralsType values of the Dobot DLL headers.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UART4PeripheralsModel {
+    Uart,
+    Wifi,
+    Bluetooth,
+    /// CH375 USB host board.
+    Ch375,
+    Unknown(u8),
+}
+
+impl FromParamable for UART4PeripheralsModel {
+    fn from_params(rdr: &mut Cursor<&[u8]>) -> Self {
+        match <u8 as FromParamable>::from_params(rdr) {
+            0 => UART4PeripheralsModel::Uart,
+            1 => UART4PeripheralsModel::Wifi,
+            2 => UART4PeripheralsModel::Bluetooth,
+            3 => UART4PeripheralsModel::Ch375,
+            model => UART4PeripheralsModel::Unknown(model),
+        }
+    }
+}
+
+// The FirmwareSwitchType values of the Dobot Magician communication protocol.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FirmwareSwitch {
+    NoSwitch,
+    Dobot,
+    Printing,
+    Driver1,
+    Driver2,
+    Driver3,
+    Driver4,
+    Driver5,
+}
+
+impl FirmwareSwitch {
+    /// The mode the controller reports once it runs the firmware switched to, if there is one.
+    pub fn mode(&self) -> Option<FirmwareMode> {
+        match self {
+            FirmwareSwitch::Dobot => Some(FirmwareMode::Dobot),
+            FirmwareSwitch::Printing => Some(FirmwareMode::Printing),
+            _ => None,
+        }
+    }
+}
+
+impl ToParams for FirmwareSwitch {
+    fn to_params(&self) -> std::io::Result<(usize, [u8; PARAMS_SIZE])> {
+        let mut b = [0u8; PARAMS_SIZE];
+        b[0] = match self {
+            FirmwareSwitch::NoSwitch => 0,
+            FirmwareSwitch::Dobot => 1,
+            FirmwareSwitch::Printing => 2,
+            FirmwareSwitch::Driver1 => 3,
+            FirmwareSwitch::Driver2 => 4,
+            FirmwareSwitch::Driver3 => 5,
+            FirmwareSwitch::Driver4 => 6,
+            FirmwareSwitch::Driver5 => 7,
+        };
+        Ok((1, b))
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, FromParams)]
+pub struct DeviceVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u8,
+}
+
+// The FirmwareMode values of the Dobot Magician communication protocol.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FirmwareMode {
+    Invalid,
+    Dobot,
+    /// 3D printer (Marlin) firmware.
+    Printing,
+    Offline,
+    Unknown(u8),
+}
+
+impl FromParamable for FirmwareMode {
+    fn from_params(rdr: &mut Cursor<&[u8]>) -> Self {
+        match <u8 as FromParamable>::from_params(rdr) {
+            0 => FirmwareMode::Invalid,
+            1 => FirmwareMode::Dobot,
+            2 => FirmwareMode::Printing,
+            3 => FirmwareMode::Offline,
+            mode => FirmwareMode::Unknown(mode),
+        }
+    }
+}
+
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Pose {
     pub x: f32,
@@ -86,7 +258,7 @@ pub struct Pose {
 }
 
 impl FromParams for Pose {
-    fn from_params(size: usize, params: [u8; PARAMS_SIZE]) -> Self {
+    fn from_params(_size: usize, params: [u8; PARAMS_SIZE]) -> Self {
         let mut rdr = Cursor::new(&params);
         let x = rdr.read_f32::<LittleEndian>().unwrap();
         let y = rdr.read_f32::<LittleEndian>().unwrap();
@@ -108,3 +280,44 @@ impl FromParams for Pose {
         }
     }
 }
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, ToParams, FromParams)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AngleSensorStaticError {
+    pub rear_arm_angle_error: f32,
+    pub front_arm_angle_error: f32,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, ToParams, FromParams)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AngleSensorCoef {
+    pub rear_arm_angle_coef: f32,
+    pub front_arm_angle_coef: f32,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CalibrationParams {
+    pub angle_sensor_static_error: AngleSensorStaticError,
+    pub angle_sensor_coef: AngleSensorCoef,
+    pub base_decoder_static_error: f32,
+}
+
+#[cfg(feature = "servo-tuning")]
+#[derive(Debug, Default, Copy, Clone, PartialEq, ToParams)]
+pub struct ServoControlLoop {
+    pub index: u8,
+    pub control_loop: u8,
+}
+
+#[cfg(feature = "servo-tuning")]
+#[derive(Debug, Default, Copy, Clone, PartialEq, ToParams, FromParams)]
+pub struct ServoPIDParams {
+    pub index: u8,
+    pub control_loop: u8,
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+    pub v: f32,
+    pub a: f32,
+}
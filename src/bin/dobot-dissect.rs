@@ -0,0 +1,28 @@
+//! Prints a conversation with the controller packet by packet.
+//!
+//! `dobot-dissect [FILE]` reads a hex dump or a recording made with `DobotBuilder::record` from
+//! FILE, or from stdin without one.
+
+use dobot_api::dissector::dissect_any;
+use std::fs::File;
+use std::io::Read;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("dobot-dissect: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> std::io::Result<()> {
+    let mut input = Vec::new();
+    match std::env::args_os().nth(1) {
+        Some(path) => File::open(path)?.read_to_end(&mut input)?,
+        None => std::io::stdin().read_to_end(&mut input)?,
+    };
+
+    for dissection in dissect_any(&input)? {
+        println!("{}", dissection);
+    }
+    Ok(())
+}
@@ -0,0 +1,302 @@
+//! A synchronous facade over `api::Dobot` for callers without an async runtime.
+//!
+//! Every method blocks the calling thread until the async method of the same name resolves.
+//! The connection is served by a runtime owned by the `Dobot`, so no tokio setup is needed.
+
+use crate::api::command::{
+    Command, QueueableCommand, SetEndEffectorSuctionCup, SetLostStepCmd, SetPTPCmd,
+};
+use crate::api::types::{
+    AlarmsState, AngleSensorCoef, AngleSensorStaticError, CalibrationParams, DeviceVersion,
+    EndEffectorParams, EndEffectorSuctionCapState, FirmwareMode, FirmwareSwitch, HHTTrigMode,
+    Kinematics, PTPCmd, PTPCommonParams, Pose, UART4PeripheralsModel,
+};
+#[cfg(feature = "servo-tuning")]
+use crate::api::types::{ServoControlLoop, ServoPIDParams};
+use crate::api::{
+    Diagnostics, DobotBuilder, DobotError, QueueIndex, Result, RetryPolicy, StateSnapshot,
+};
+use crate::runtime::TokioRuntime;
+use futures::StreamExt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+
+macro_rules! blocking {
+    ($($(#[$attr:meta])* fn $name:ident(&self $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        $(
+            $(#[$attr])*
+            pub fn $name(&self $(, $arg: $ty)*) -> $ret {
+                self.block_on(self.dobot.$name($($arg),*))
+            }
+        )*
+    };
+}
+
+pub struct Dobot {
+    // Dropped before the runtime so that the driver is told to shut down first.
+    dobot: crate::api::Dobot,
+    runtime: Arc<Runtime>,
+}
+
+impl Dobot {
+    /// Connects with `builder`, e.g. `blocking::Dobot::connect(Dobot::builder().port(port))`.
+    pub fn connect(builder: DobotBuilder) -> Result<Self> {
+        let runtime = Builder::new()
+            .threaded_scheduler()
+            .core_threads(1)
+            .enable_all()
+            .build()
+            .map_err(DobotError::Io)?;
+        let dobot = runtime
+            .handle()
+            .block_on(builder.runtime(Arc::new(TokioRuntime)).connect())?;
+
+        Ok(Self {
+            dobot,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.handle().block_on(future)
+    }
+
+    /// The async `Dobot` behind this one, e.g. to hand to code that does run a runtime.
+    pub fn as_async(&self) -> &crate::api::Dobot {
+        &self.dobot
+    }
+
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.dobot.diagnostics()
+    }
+
+    /// See `api::Dobot::with_retry_policy`. The returned `Dobot` shares the connection and the
+    /// runtime with this one.
+    pub fn with_retry_policy(&self, retry_policy: RetryPolicy) -> Dobot {
+        Dobot {
+            dobot: self.dobot.with_retry_policy(retry_policy),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    /// Sends `C` right away and blocks for its response, see `api::Dobot::execute`. Covers the
+    /// commands without a method of their own, and immediate writes such as
+    /// `execute::<SetPTPCommonParams>`.
+    pub fn execute<C: Command>(&self, params: C::Params) -> Result<C::Response> {
+        self.block_on(self.dobot.execute::<C>(params))
+    }
+
+    /// Blocks until the controller has put `C` into its command queue, see
+    /// `api::Dobot::execute_queued`.
+    pub fn execute_queued<C: QueueableCommand>(&self, params: C::Params) -> Result<QueueIndex> {
+        self.block_on(self.dobot.execute_queued::<C>(params).accepted())
+    }
+
+    /// Waits until the controller has executed the command at `index`, or fails with
+    /// `DobotError::Timeout` once `timeout` has passed.
+    pub fn wait_queued_command(&self, index: QueueIndex, timeout: Duration) -> Result<()> {
+        self.block_on(self.dobot.wait_queued_command_timeout(index, timeout))
+    }
+
+    pub fn wait_queued_commands(&self, indices: &[QueueIndex], timeout: Duration) -> Result<()> {
+        self.block_on(self.dobot.wait_queued_commands_timeout(indices, timeout))
+    }
+
+    /// Blocks until the arm has moved to `ptp_cmd`, see `api::Dobot::move_to`.
+    pub fn move_to(&self, ptp_cmd: PTPCmd) -> Result<()> {
+        self.block_on(self.dobot.move_to(ptp_cmd).await_done())
+    }
+
+    /// Blocks until the suction cap has switched, see `api::Dobot::suction_cap`.
+    pub fn suction_cap(&self, suctions_cap_state: EndEffectorSuctionCapState) -> Result<()> {
+        self.block_on(self.dobot.suction_cap(suctions_cap_state).await_done())
+    }
+
+    /// Blocks until the lost step check has run, failing with `DobotError::LostStep` if the arm
+    /// lost steps during the moves before it. See `api::Dobot::detect_lost_step`.
+    pub fn detect_lost_step(&self) -> Result<()> {
+        self.block_on(self.dobot.detect_lost_step().await_done())
+    }
+
+    /// Blocks until the controller has queued setting the end effector's offsets, see
+    /// `api::Dobot::set_end_effector_params`.
+    pub fn set_end_effector_params(
+        &self,
+        end_effector_params: EndEffectorParams,
+    ) -> Result<QueueIndex> {
+        self.block_on(
+            self.dobot
+                .set_end_effector_params(end_effector_params)
+                .accepted(),
+        )
+    }
+
+    /// Blocks until the controller has queued setting the point-to-point ratios, see
+    /// `api::Dobot::set_PTP_common_params`.
+    #[allow(non_snake_case)]
+    pub fn set_PTP_common_params(&self, params: PTPCommonParams) -> Result<QueueIndex> {
+        self.block_on(self.dobot.set_PTP_common_params(params).accepted())
+    }
+
+    /// Blocks until the controller has queued the move, see `api::Dobot::move_to`.
+    #[deprecated(note = "use `execute_queued::<SetPTPCmd>`")]
+    pub fn set_ptp_cmd(&self, ptp_cmd: PTPCmd) -> Result<QueueIndex> {
+        self.execute_queued::<SetPTPCmd>(ptp_cmd)
+    }
+
+    /// Blocks until the controller has queued switching the suction cap, see
+    /// `api::Dobot::suction_cap`.
+    #[deprecated(note = "use `execute_queued::<SetEndEffectorSuctionCup>`")]
+    pub fn set_end_effector_suctions_cap(
+        &self,
+        suctions_cap_state: EndEffectorSuctionCapState,
+    ) -> Result<QueueIndex> {
+        self.execute_queued::<SetEndEffectorSuctionCup>(suctions_cap_state.into())
+    }
+
+    /// Blocks until the controller has queued the lost step check, see
+    /// `api::Dobot::detect_lost_step`.
+    #[deprecated(note = "use `execute_queued::<SetLostStepCmd>`")]
+    pub fn set_lost_step_cmd(&self) -> Result<QueueIndex> {
+        self.execute_queued::<SetLostStepCmd>(())
+    }
+
+    /// Blocks for every key release on the handheld teaching trigger, see
+    /// `api::Dobot::hht_trig_events`.
+    pub fn hht_trig_events(
+        &self,
+        poll_interval: Duration,
+    ) -> impl Iterator<Item = Result<()>> + '_ {
+        let mut events = Box::pin(self.dobot.hht_trig_events(poll_interval));
+        std::iter::from_fn(move || self.block_on(events.next()))
+    }
+
+    /// Blocks for every state snapshot, see `api::Dobot::subscribe_state`.
+    pub fn subscribe_state(
+        &self,
+        interval: Duration,
+        digital_inputs: &[u8],
+    ) -> impl Iterator<Item = StateSnapshot> + '_ {
+        let mut snapshots = Box::pin(
+            self.dobot
+                .subscribe_state_with_inputs(interval, digital_inputs),
+        );
+        std::iter::from_fn(move || self.block_on(snapshots.next()))
+    }
+
+    pub fn disconnect_dobot(&self) {
+        self.block_on(self.dobot.disconnect_dobot())
+    }
+
+    blocking! {
+        fn get_queue_index(&self) -> Result<QueueIndex>;
+        fn get_device_sn(&self) -> Result<String>;
+        fn set_device_name(&self, name: &str) -> Result<()>;
+        fn get_device_name(&self) -> Result<String>;
+        fn get_device_version(&self) -> Result<DeviceVersion>;
+        fn set_queued_cmd_start_exec(&self) -> Result<()>;
+        fn set_queued_cmd_stop_exec(&self) -> Result<()>;
+        fn set_queued_cmd_force_stop_exec(&self) -> Result<()>;
+        fn set_queued_cmd_clear(&self) -> Result<()>;
+        fn get_end_effector_suctions_cap(&self) -> Result<EndEffectorSuctionCapState>;
+        fn get_io_di(&self, address: u8) -> Result<bool>;
+        fn set_hht_trig_mode(&self, mode: HHTTrigMode) -> Result<()>;
+        fn set_hht_trig_output_enabled(&self, is_enabled: bool) -> Result<()>;
+        fn get_hht_trig_output(&self) -> Result<bool>;
+        fn get_alarms_state(&self) -> Result<AlarmsState>;
+        fn clear_all_alarms_state(&self) -> Result<()>;
+        fn set_lost_step_params(&self, threshold: f32) -> Result<()>;
+        fn reset_pose(
+            &self,
+            manual: bool,
+            rear_arm_angle: f32,
+            front_arm_angle: f32
+        ) -> Result<()>;
+        fn get_kinematics(&self) -> Result<Kinematics>;
+        fn set_angle_sensor_static_error(&self, static_error: AngleSensorStaticError) -> Result<()>;
+        fn get_angle_sensor_static_error(&self) -> Result<AngleSensorStaticError>;
+        fn set_angle_sensor_coef(&self, coef: AngleSensorCoef) -> Result<()>;
+        fn get_angle_sensor_coef(&self) -> Result<AngleSensorCoef>;
+        fn set_base_decoder_static_error(&self, static_error: f32) -> Result<()>;
+        fn get_base_decoder_static_error(&self) -> Result<f32>;
+        fn backup_calibration_params(&self) -> Result<CalibrationParams>;
+        fn restore_calibration_params(&self, params: &CalibrationParams) -> Result<()>;
+        fn get_firmware_mode(&self) -> Result<FirmwareMode>;
+        fn set_firmware_switch(
+            &self,
+            switch: FirmwareSwitch,
+            reconnect_timeout: Duration
+        ) -> Result<()>;
+        fn get_uart4_peripherals_model(&self) -> Result<UART4PeripheralsModel>;
+        fn set_uart4_peripherals_enabled(&self, is_enabled: bool) -> Result<()>;
+        fn get_uart4_peripherals_enabled(&self) -> Result<bool>;
+        fn set_pulse_mode_enabled(&self, is_enabled: bool) -> Result<()>;
+        fn get_pulse_mode_enabled(&self) -> Result<bool>;
+        fn get_ptp_time(&self, ptp_cmd: PTPCmd) -> Result<Duration>;
+        #[cfg(feature = "servo-tuning")]
+        fn set_servo_pid_params(&self, params: ServoPIDParams) -> Result<()>;
+        #[cfg(feature = "servo-tuning")]
+        fn get_servo_pid_params(&self, control_loop: ServoControlLoop) -> Result<ServoPIDParams>;
+        #[cfg(feature = "servo-tuning")]
+        fn set_servo_control_loop(&self, control_loop: ServoControlLoop) -> Result<()>;
+        #[cfg(feature = "servo-tuning")]
+        fn save_servo_pid_params(&self, control_loop: ServoControlLoop) -> Result<()>;
+        fn get_pose(&self) -> Result<Pose>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::command::SetPTPCommonParams;
+    use crate::emulator::Emulator;
+
+    const WAIT_TIME: Duration = Duration::from_millis(50);
+
+    fn connect() -> Dobot {
+        Dobot::connect(
+            DobotBuilder::new()
+                .transport(Box::new(Emulator::new()))
+                .ack_timeout(WAIT_TIME),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn commands_block_until_answered() {
+        let dobot = connect();
+        let index = dobot
+            .execute_queued::<SetPTPCmd>(PTPCmd::default())
+            .unwrap();
+
+        dobot.wait_queued_command(index, WAIT_TIME).unwrap();
+        dobot
+            .execute::<SetPTPCommonParams>(PTPCommonParams::default())
+            .unwrap();
+        dobot.move_to(PTPCmd::default()).unwrap();
+        assert_eq!(dobot.get_pose().unwrap().x, 1.0);
+    }
+
+    #[test]
+    fn wait_queued_command_times_out() {
+        let dobot = connect();
+
+        assert!(matches!(
+            dobot.wait_queued_command(QueueIndex(100), WAIT_TIME),
+            Err(DobotError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn can_be_used_from_several_threads() {
+        let dobot = connect();
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| dobot.get_pose().unwrap());
+            }
+        });
+    }
+}
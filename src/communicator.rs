@@ -1,128 +1,797 @@
-use std::time::Duration;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::channel::oneshot;
+use futures::channel::{mpsc, oneshot};
+use futures::{select, FutureExt, StreamExt};
 use nom::lib::std::collections::VecDeque;
 
-use crate::connector::Connector;
-use crate::protocol::message::Message;
+use crate::connector::{ConnectorError, Transport};
+use crate::protocol::descriptor::{validate, Violation};
+use crate::protocol::message::{FromParams, Message};
 use crate::protocol::packet::Packet;
+use crate::protocol::protocol_id::ProtocolID;
+use crate::runtime::Runtime;
 
 const MAX_MESSAGES: usize = 128;
+const MAX_IN_FLIGHT: usize = 8;
+const LEFT_SPACE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const QUEUE_INDEX_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const DRAIN_TIME: Duration = Duration::from_millis(20);
 
 #[derive(Debug)]
 pub enum CommunicateStatus {
-    NoError(Message),
+    // Boxed so that errors carrying a status stay small.
+    NoError(Box<Message>),
     BufferFull,
     Timeout,
     InvalidParams,
+    IoError(std::io::Error),
+    /// The communicator is gone, so the request can never be answered.
+    Disconnected,
+    /// The request was dropped before it could complete, e.g. by a reconnect.
+    Cancelled,
+    /// The ACK of a queued command got lost and a later command took its queue index, so the
+    /// controller never enqueued it. It was not resent, as that would change the order of the
+    /// queue.
+    Lost,
+    /// The protocol does not allow the request, e.g. queuing a read. It was not sent.
+    Illegal(Violation),
+}
+
+/// How often and how patiently a command is retried when its ACK does not arrive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of times a command is sent at most, the first attempt included.
+    pub max_attempts: usize,
+    /// How long to wait for the ACK of each attempt.
+    pub timeout: Duration,
+    /// Delay before the first retry, doubled for every further one.
+    pub backoff: Duration,
+    /// Whether queued commands are resent too. A queued command whose ACK got lost is only
+    /// resent if it was the only queued command in flight and the controller's current queue
+    /// index stays at the command before it for `timeout`. A command still running for longer
+    /// than that may hold the queue just the same, so a resent motion may run twice.
+    pub retry_queued: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            timeout: Duration::from_millis(500),
+            backoff: Duration::from_millis(0),
+            retry_queued: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempts: usize) -> Duration {
+        self.backoff * 2u32.saturating_pow(attempts.saturating_sub(1) as u32)
+    }
 }
 
 struct MessageHandler {
     message: Message,
-    sender: oneshot::Sender<CommunicateStatus>,
+    // `None` for the left space and queue index queries the communicator issues on its own.
+    sender: Option<oneshot::Sender<CommunicateStatus>>,
+    policy: RetryPolicy,
+    attempts: usize,
+    deadline: Instant,
+    not_before: Option<Instant>,
+    // Whether no other queued command was in flight when this one was last sent.
+    sent_alone: bool,
 }
 
+enum Request {
+    Message(Box<MessageHandler>),
+    Reconnect(Duration, oneshot::Sender<Result<(), ConnectorError>>),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Diagnostics {
+    /// Replies that matched no in-flight command, e.g. ACKs arriving after their timeout.
+    pub stale_replies: u64,
+    pub timeouts: u64,
+}
+
+#[derive(Default)]
+struct DiagnosticsCounters {
+    stale_replies: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct CommunicatorHandle {
+    sender: mpsc::UnboundedSender<Request>,
+    diagnostics: Arc<DiagnosticsCounters>,
+}
+
+/// Keeps up to `MAX_IN_FLIGHT` commands on the wire at once. The controller answers in order, so
+/// every ACK is matched against the oldest in-flight command with the same id and ctrl bits.
+///
+/// Queued commands are throttled by `left_space`, an estimate of the free slots in the
+/// firmware's command queue. It is decremented for every queued command sent and only refreshed
+/// with `ProtocolQueuedCmdLeftSpace` once it reaches zero. Polls that have to be repeated, e.g.
+/// while the queue stays full, are scheduled like any other exchange, so the ACKs of the commands
+/// in flight keep being read in the meantime.
+///
+/// A queued command whose ACK is lost is not resent right away, as it may have been enqueued
+/// anyway. The controller numbers every command it enqueues, so the index of a later queued
+/// command tells how many of the lost commands made it. Without a later command, the lost ones
+/// are verified with `ProtocolQueuedCmdCurrentIndex` instead: once the queue reaches the index
+/// the last of them would hold, all of them were enqueued. ACKs carry no parameters, though, so a
+/// lost request followed by an enqueued command with the same id is indistinguishable from a lost
+/// reply to that command.
 pub struct Communicator {
-    connector: Connector,
-    message_handlers: VecDeque<MessageHandler>,
+    connector: Box<dyn Transport>,
+    receiver: mpsc::UnboundedReceiver<Request>,
+    pending: VecDeque<MessageHandler>,
+    in_flight: VecDeque<MessageHandler>,
     left_space: usize,
-    wait_time: Duration,
+    // When the left space or the queue index may be polled again, after a poll that told nothing.
+    left_space_poll_at: Option<Instant>,
+    queue_index_poll_at: Option<Instant>,
+    // Index of the last command known to be enqueued; `None` until the first queued ACK.
+    last_queue_index: Option<u64>,
+    // Queued commands that got no ACK since `last_queue_index`, in the order they were sent.
+    unverified: VecDeque<MessageHandler>,
+    retry_policy: RetryPolicy,
+    diagnostics: Arc<DiagnosticsCounters>,
+    runtime: Arc<dyn Runtime>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum Control {
-    Retry,
-    Abort,
+impl CommunicatorHandle {
+    pub fn insert_message(
+        &self,
+        message: &Message,
+        policy: RetryPolicy,
+    ) -> oneshot::Receiver<CommunicateStatus> {
+        let (tx, rx) = oneshot::channel::<CommunicateStatus>();
+
+        let _ = self
+            .sender
+            .unbounded_send(Request::Message(Box::new(MessageHandler::new(
+                message.clone(),
+                Some(tx),
+                policy,
+            ))));
+        rx
+    }
+
+    pub fn diagnostics(&self) -> Diagnostics {
+        Diagnostics {
+            stale_replies: self.diagnostics.stale_replies.load(Ordering::Relaxed),
+            timeouts: self.diagnostics.timeouts.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reconnect(
+        &self,
+        wait_duration: Duration,
+    ) -> oneshot::Receiver<Result<(), ConnectorError>> {
+        let (tx, rx) = oneshot::channel();
+
+        let _ = self
+            .sender
+            .unbounded_send(Request::Reconnect(wait_duration, tx));
+        rx
+    }
 }
 
 impl Communicator {
-    pub fn new(connector: Connector, wait_time: Option<Duration>) -> Self {
-        let wait_time = wait_time.unwrap_or(Duration::from_millis(500));
-        Communicator {
-            connector,
-            message_handlers: VecDeque::new(),
-            left_space: 128,
-            wait_time,
+    pub fn new(
+        connector: Box<dyn Transport>,
+        retry_policy: RetryPolicy,
+        runtime: Arc<dyn Runtime>,
+    ) -> (Self, CommunicatorHandle) {
+        let (sender, receiver) = mpsc::unbounded();
+        let diagnostics = Arc::new(DiagnosticsCounters::default());
+        (
+            Communicator {
+                connector,
+                receiver,
+                pending: VecDeque::new(),
+                in_flight: VecDeque::new(),
+                left_space: 0,
+                left_space_poll_at: None,
+                queue_index_poll_at: None,
+                last_queue_index: None,
+                unverified: VecDeque::new(),
+                retry_policy,
+                diagnostics: diagnostics.clone(),
+                runtime,
+            },
+            CommunicatorHandle {
+                sender,
+                diagnostics,
+            },
+        )
+    }
+
+    /// Runs one step: takes new requests, fills the pipeline and reads at most one ACK.
+    /// Returns `false` once every handle has been dropped and nothing is left to do.
+    pub async fn run(&mut self) -> bool {
+        if self.pending.is_empty() && self.in_flight.is_empty() && self.unverified.is_empty() {
+            match self.receiver.next().await {
+                Some(request) => self.handle_request(request).await,
+                None => return false,
+            }
+        }
+        while let Ok(request) = self.receiver.try_recv() {
+            self.handle_request(request).await;
         }
+
+        self.send_messages().await;
+        if !self.in_flight.is_empty() {
+            self.receive_ack().await;
+        } else if let Some(wake_at) = self.wake_at() {
+            let mut delay = self
+                .runtime
+                .delay(wake_at.saturating_duration_since(Instant::now()))
+                .fuse();
+            select! {
+                request = self.receiver.next() => match request {
+                    Some(request) => self.handle_request(request).await,
+                    None => delay.await,
+                },
+                () = delay => {},
+            }
+        }
+        true
     }
 
-    pub fn insert_message(&mut self, message: &Message) -> oneshot::Receiver<CommunicateStatus> {
-        let (tx, rx) = oneshot::channel::<CommunicateStatus>();
+    /// The next time something that is waited for may be sent. What is due already has been
+    /// sent by `send_messages`, or waits for something else.
+    fn wake_at(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let not_before = self.pending.front().and_then(|mh| mh.not_before);
+        [
+            not_before,
+            self.left_space_poll_at,
+            self.queue_index_poll_at,
+        ]
+        .iter()
+        .flatten()
+        .filter(|t| **t > now)
+        .min()
+        .copied()
+    }
 
-        if self.message_handlers.len() > MAX_MESSAGES {
-            tx.send(CommunicateStatus::BufferFull).unwrap();
-            return rx;
+    async fn handle_request(&mut self, request: Request) {
+        match request {
+            Request::Message(mh) => {
+                if let Err(violation) = validate(&mh.message) {
+                    mh.complete(CommunicateStatus::Illegal(violation));
+                    return;
+                }
+                if self.pending.len() >= MAX_MESSAGES {
+                    mh.complete(CommunicateStatus::BufferFull);
+                    return;
+                }
+                self.pending.push_back(*mh);
+            }
+            Request::Reconnect(wait_duration, sender) => {
+                // Whether the old device acted on these is unknown; only untouched requests
+                // survive the reconnect.
+                for mh in self.in_flight.drain(..) {
+                    mh.complete(CommunicateStatus::Cancelled);
+                }
+                let (retried, pending) = self
+                    .pending
+                    .drain(..)
+                    .partition::<Vec<_>, _>(|mh| mh.attempts > 0);
+                for mh in retried {
+                    mh.complete(CommunicateStatus::Cancelled);
+                }
+                self.pending = pending.into();
+                for mh in self.unverified.drain(..) {
+                    mh.complete(CommunicateStatus::Cancelled);
+                }
+                self.left_space = 0;
+                self.left_space_poll_at = None;
+                self.queue_index_poll_at = None;
+                self.last_queue_index = None;
+                let _ = sender.send(self.connector.reconnect(wait_duration).await);
+            }
         }
-        self.message_handlers.push_back(MessageHandler {
-            message: message.clone(),
-            sender: tx,
-        });
-        rx
     }
 
-    pub async fn run(&mut self) {
-        let mh = self.message_handlers.pop_front();
-        if mh.is_none() {
-            return;
+    async fn send_messages(&mut self) {
+        if !self.unverified.is_empty() {
+            self.query_queue_index().await;
         }
-        let mh = mh.unwrap();
-        if mh.message.is_queued != 0 {
-            let message = Message::new_get_left_space();
-            let mut ctl = self.send_and_wait_command_ack(&message).await;
-            let mut num_retry = 0i32;
-            while check_retry(&ctl) && num_retry < 3 {
-                ctl = self.send_and_wait_command_ack(&message).await;
-                num_retry += 1;
+        while self.in_flight.len() < MAX_IN_FLIGHT {
+            let is_queued = match self.pending.front() {
+                Some(mh) if mh.not_before.is_some_and(|t| t > Instant::now()) => break,
+                Some(mh) => mh.message.is_queued != 0,
+                None => break,
+            };
+
+            // Nothing may be enqueued before the lost commands are settled.
+            if is_queued && !self.unverified.is_empty() {
+                break;
+            }
+            if is_queued && self.left_space == 0 {
+                self.query_left_space().await;
+                break;
             }
-            if ctl.is_err() {
-                mh.sender.send(CommunicateStatus::Timeout).unwrap();
-                return; // このメッセージは終了
+
+            let mh = self.pending.pop_front().unwrap();
+            if is_queued {
+                self.left_space -= 1;
             }
+            self.send(mh).await;
         }
-        let mut ctl = self.send_and_wait_command_ack(&mh.message).await;
-        let mut num_retry = 0;
-        while check_retry(&ctl) && num_retry < 3 {
-            ctl = self.send_and_wait_command_ack(&mh.message).await;
-            num_retry += 1;
+    }
+
+    async fn query_left_space(&mut self) {
+        if self.left_space_poll_at.is_some_and(|t| t > Instant::now()) {
+            return;
+        }
+        if !self.in_flight.iter().any(is_left_space_query) {
+            self.left_space_poll_at = None;
+            self.send(MessageHandler::new(
+                Message::new_get_left_space(),
+                None,
+                self.retry_policy,
+            ))
+            .await;
         }
-        if ctl.is_err() {
-            mh.sender.send(CommunicateStatus::Timeout).unwrap();
-            return; // このメッセージは終了
+    }
+
+    /// Reads the current queue index unless queued commands are still in flight: their ACKs carry
+    /// an index too.
+    async fn query_queue_index(&mut self) {
+        if self.queue_index_poll_at.is_some_and(|t| t > Instant::now())
+            || self
+                .in_flight
+                .iter()
+                .any(|mh| mh.message.is_queued != 0 || is_queue_index_query(mh))
+        {
+            return;
         }
-        mh.sender
-            .send(CommunicateStatus::NoError(ctl.unwrap()))
-            .unwrap();
+        self.queue_index_poll_at = None;
+        self.send(MessageHandler::new(
+            Message::new_get_queue_index(),
+            None,
+            self.retry_policy,
+        ))
+        .await;
     }
 
-    async fn send_and_wait_command_ack(&mut self, message: &Message) -> Result<Message, Control> {
-        self.connector
-            .write_packet(&Packet::from_message(message))
+    async fn send(&mut self, mut mh: MessageHandler) {
+        mh.sent_alone = !self.in_flight.iter().any(|mh| mh.message.is_queued != 0);
+        match self
+            .connector
+            .write_packet(&Packet::from_message(&mh.message))
             .await
-            .unwrap();
+        {
+            Ok(_) => {
+                mh.attempts += 1;
+                mh.deadline = Instant::now() + mh.policy.timeout;
+                self.in_flight.push_back(mh);
+            }
+            Err(e) => {
+                if is_queue_index_query(&mh) {
+                    for mh in self.unverified.drain(..) {
+                        mh.complete(CommunicateStatus::IoError(std::io::Error::new(
+                            e.kind(),
+                            e.to_string(),
+                        )));
+                    }
+                }
+                mh.complete(CommunicateStatus::IoError(e));
+            }
+        }
+    }
 
+    async fn receive_ack(&mut self) {
+        let deadline = self.in_flight.iter().map(|mh| mh.deadline).min().unwrap();
+        // Woken up early for a scheduled poll, the read just comes back empty.
+        let deadline = self
+            .wake_at()
+            .map_or(deadline, |wake_at| wake_at.min(deadline));
         match self
             .connector
-            .read_packet_with_timeout(self.wait_time)
+            .read_packet_with_timeout(deadline.saturating_duration_since(Instant::now()))
             .await
         {
-            Some(packet) => {
+            Ok(Some(packet)) => {
                 let mes = packet.to_message();
-                if mes.id != message.id {
-                    return Err(Control::Retry);
+                let position = match self.match_ack(&mes) {
+                    Some(position) => position,
+                    None => {
+                        self.diagnostics
+                            .stale_replies
+                            .fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                };
+
+                // Everything sent before the acknowledged command got no reply.
+                let lost: Vec<_> = self.in_flight.drain(..position).collect();
+                self.retry(lost);
+
+                let mh = self.in_flight.pop_front().unwrap();
+                if mes.is_queued != 0 {
+                    self.settle(mes.queue_index());
+                }
+                if is_left_space_query(&mh) {
+                    self.left_space =
+                        u32::from_params(mes.params_len as usize, mes.params) as usize;
+                    if self.left_space == 0 {
+                        self.left_space_poll_at = Some(Instant::now() + LEFT_SPACE_POLL_INTERVAL);
+                    }
+                } else if is_queue_index_query(&mh) {
+                    if !self.verify(u64::from_params(mes.params_len as usize, mes.params)) {
+                        self.queue_index_poll_at = Some(Instant::now() + QUEUE_INDEX_POLL_INTERVAL);
+                    }
+                } else {
+                    mh.complete(CommunicateStatus::NoError(Box::new(mes)));
+                }
+            }
+            Ok(None) => {
+                let now = Instant::now();
+                let (lost, in_flight) = self
+                    .in_flight
+                    .drain(..)
+                    .partition::<Vec<_>, _>(|mh| mh.deadline <= now);
+                self.in_flight = in_flight.into();
+                if lost.is_empty() {
+                    return;
+                }
+                self.diagnostics.timeouts.fetch_add(1, Ordering::Relaxed);
+                self.retry(lost);
+                // Draining now would swallow the ACKs of commands still in flight.
+                if self.in_flight.is_empty() {
+                    self.drain_stale_replies().await;
+                }
+            }
+            Err(e) => {
+                for mh in self.in_flight.drain(..) {
+                    mh.complete(CommunicateStatus::IoError(std::io::Error::new(
+                        e.kind(),
+                        e.to_string(),
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Finds the in-flight command `mes` answers. Several queued commands with the same id may
+    /// be in flight, so a queued ACK is matched by its index where possible: the controller
+    /// hands out consecutive indices, starting after the ones the unverified commands may hold.
+    fn match_ack(&self, mes: &Message) -> Option<usize> {
+        let answers = |mh: &MessageHandler| {
+            mh.message.id == mes.id
+                && mh.message.rw == mes.rw
+                && mh.message.is_queued == mes.is_queued
+        };
+
+        if let (true, Some(last)) = (mes.is_queued != 0, self.last_queue_index) {
+            let offset = mes.queue_index().wrapping_sub(last).wrapping_sub(1);
+            let offset: usize = offset.try_into().unwrap_or(usize::MAX);
+            match offset.checked_sub(self.unverified.len()) {
+                // A late ACK of a command that is already being verified.
+                None if answers(&self.unverified[offset]) => return None,
+                None => {}
+                Some(offset) => {
+                    let by_index = self
+                        .in_flight
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, mh)| mh.message.is_queued != 0)
+                        .nth(offset)
+                        .map(|(position, _)| position);
+                    if let Some(position) =
+                        by_index.filter(|position| answers(&self.in_flight[*position]))
+                    {
+                        return Some(position);
+                    }
                 }
-                Ok(mes)
             }
+        }
+        self.in_flight.iter().position(answers)
+    }
+
+    /// Discards replies that trickle in after a timeout so that they are not mistaken for the
+    /// ACKs of the commands about to be resent.
+    async fn drain_stale_replies(&mut self) {
+        while let Ok(Some(_)) = self.connector.read_packet_with_timeout(DRAIN_TIME).await {
+            self.diagnostics
+                .stale_replies
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn retry(&mut self, lost: Vec<MessageHandler>) {
+        let mut resent = vec![];
+        for mut mh in lost {
+            if mh.sender.is_none() {
+                continue;
+            } else if mh.message.is_queued != 0 && self.last_queue_index.is_none() {
+                // There is no index to tell from whether it was enqueued.
+                mh.complete(CommunicateStatus::Timeout);
+            } else if mh.message.is_queued != 0 {
+                mh.deadline = Instant::now() + mh.policy.timeout;
+                self.unverified.push_back(mh);
+            } else if mh.attempts >= mh.policy.max_attempts {
+                mh.complete(CommunicateStatus::Timeout);
+            } else {
+                resent.push(mh);
+            }
+        }
+        self.resend(resent);
+    }
+
+    fn resend(&mut self, messages: Vec<MessageHandler>) {
+        for mut mh in messages.into_iter().rev() {
+            mh.not_before = Some(Instant::now() + mh.policy.backoff(mh.attempts));
+            self.pending.push_front(mh);
+        }
+    }
+
+    /// Settles the unverified commands given the index of the next queued command that got an
+    /// ACK. Every index in between belongs to one of them.
+    fn settle(&mut self, index: u64) {
+        let last = self.last_queue_index.replace(index);
+        if self.unverified.is_empty() {
+            return;
+        }
+        let unverified: Vec<_> = self.unverified.drain(..).collect();
+        let last = match last {
+            Some(last) => last,
             None => {
-                eprintln!("timeout");
-                Err(Control::Retry)
+                for mh in unverified {
+                    mh.complete(CommunicateStatus::Timeout);
+                }
+                return;
+            }
+        };
+
+        let enqueued = index.wrapping_sub(last).wrapping_sub(1);
+        if enqueued == unverified.len() as u64 {
+            for (i, mh) in unverified.into_iter().enumerate() {
+                let ack = mh.message.new_queue_ack(last.wrapping_add(i as u64 + 1));
+                mh.complete(CommunicateStatus::NoError(Box::new(ack)));
+            }
+        } else if enqueued == 0 {
+            // The later command went in ahead of them, so resending would change the order.
+            for mh in unverified {
+                mh.complete(CommunicateStatus::Lost);
+            }
+        } else {
+            // Some of them were enqueued, but there is no telling which.
+            for mh in unverified {
+                mh.complete(CommunicateStatus::Timeout);
+            }
+        }
+    }
+
+    /// Settles the unverified commands given the index of the queued command the controller is
+    /// executing. Returns `false` while that tells nothing yet and the commands still wait.
+    fn verify(&mut self, current: u64) -> bool {
+        let last = match self.last_queue_index {
+            Some(last) if !self.unverified.is_empty() => last,
+            _ => return true,
+        };
+        let count = self.unverified.len() as u64;
+        if current >= last.wrapping_add(count) {
+            // The queue has been through every index they may hold.
+            for (i, mh) in self.unverified.drain(..).enumerate() {
+                let ack = mh.message.new_queue_ack(last.wrapping_add(i as u64 + 1));
+                mh.complete(CommunicateStatus::NoError(Box::new(ack)));
             }
+            self.last_queue_index = Some(last.wrapping_add(count));
+            return true;
+        }
+        if self
+            .unverified
+            .iter()
+            .any(|mh| mh.deadline > Instant::now())
+        {
+            return false;
+        }
+
+        let unverified: Vec<_> = self.unverified.drain(..).collect();
+        // The queue stopped at the command before it, and no other command may have taken its
+        // index. It may still sit in the queue behind a long motion, though.
+        let resend = match &unverified[..] {
+            [mh] => {
+                current == last
+                    && mh.sent_alone
+                    && mh.policy.retry_queued
+                    && mh.attempts < mh.policy.max_attempts
+            }
+            _ => false,
+        };
+        if resend {
+            self.resend(unverified);
+        } else {
+            for mh in unverified {
+                mh.complete(CommunicateStatus::Timeout);
+            }
+            // Whether they took indices is unknown, so the next index to expect is too.
+            self.last_queue_index = None;
+        }
+        true
+    }
+}
+
+fn is_left_space_query(mh: &MessageHandler) -> bool {
+    mh.sender.is_none() && mh.message.id == ProtocolID::ProtocolQueuedCmdLeftSpace as u8
+}
+
+fn is_queue_index_query(mh: &MessageHandler) -> bool {
+    mh.sender.is_none() && mh.message.id == ProtocolID::ProtocolQueuedCmdCurrentIndex as u8
+}
+
+impl MessageHandler {
+    fn new(
+        message: Message,
+        sender: Option<oneshot::Sender<CommunicateStatus>>,
+        policy: RetryPolicy,
+    ) -> Self {
+        Self {
+            message,
+            sender,
+            policy,
+            attempts: 0,
+            deadline: Instant::now(),
+            not_before: None,
+            sent_alone: false,
+        }
+    }
+
+    fn complete(self, status: CommunicateStatus) {
+        // The caller may have dropped its receiver.
+        if let Some(sender) = self.sender {
+            let _ = sender.send(status);
         }
     }
 }
 
-fn check_retry<T>(ctl: &Result<T, Control>) -> bool {
-    match ctl {
-        Err(Control::Retry) => true,
-        _ => false,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::types::PTPCmd;
+    use crate::emulator::{Emulator, Fault};
+    use crate::protocol::message::ReadWrite;
+    use crate::runtime::default_runtime;
+    use futures::future::{join, join_all, FutureExt};
+    use tokio::time::delay_for;
+
+    const WAIT_TIME: Duration = Duration::from_millis(50);
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            timeout: WAIT_TIME,
+            ..Default::default()
+        }
+    }
+
+    /// Sends `messages` before the communicator starts, so that they are all pending at once.
+    fn start(
+        emulator: &Emulator,
+        messages: &[Message],
+    ) -> (
+        CommunicatorHandle,
+        Vec<oneshot::Receiver<CommunicateStatus>>,
+    ) {
+        let runtime = default_runtime().unwrap();
+        let (mut communicator, handle) =
+            Communicator::new(Box::new(emulator.clone()), retry_policy(), runtime.clone());
+        let replies = messages
+            .iter()
+            .map(|message| handle.insert_message(message, retry_policy()))
+            .collect();
+        runtime.spawn(async move { while communicator.run().await {} }.boxed());
+        (handle, replies)
+    }
+
+    fn get_pose() -> Message {
+        Message::new::<()>(ProtocolID::ProtocolGetPose, ReadWrite::Read, false, &None)
+    }
+
+    fn move_to() -> Message {
+        Message::new(
+            ProtocolID::ProtocolPTPCmd,
+            ReadWrite::Write,
+            true,
+            &Some(PTPCmd::default()),
+        )
+    }
+
+    fn reply(status: Result<CommunicateStatus, oneshot::Canceled>) -> Message {
+        match status.unwrap() {
+            CommunicateStatus::NoError(message) => *message,
+            status => panic!("no reply: {:?}", status),
+        }
+    }
+
+    fn pose_x(message: &Message) -> f32 {
+        f32::from_le_bytes([
+            message.params[0],
+            message.params[1],
+            message.params[2],
+            message.params[3],
+        ])
+    }
+
+    #[tokio::test]
+    async fn several_commands_are_in_flight_at_once() {
+        let emulator = Emulator::new();
+        let (_handle, replies) = start(&emulator, &[get_pose(), get_pose(), get_pose()]);
+
+        let poses: Vec<_> = join_all(replies)
+            .await
+            .into_iter()
+            .map(|status| pose_x(&reply(status)))
+            .collect();
+        assert_eq!(poses, vec![1.0, 2.0, 3.0]);
+        assert_eq!(emulator.max_unread(), 3);
+    }
+
+    #[tokio::test]
+    async fn left_space_is_only_refreshed_once_used_up() {
+        let emulator = Emulator::new();
+        emulator.hold_queue();
+        emulator.set_queue_capacity(3);
+        let (handle, replies) = start(&emulator, &[move_to(), move_to(), move_to()]);
+
+        let indices: Vec<_> = join_all(replies)
+            .await
+            .into_iter()
+            .map(|status| reply(status).queue_index())
+            .collect();
+        assert_eq!(indices, vec![1, 2, 3]);
+        assert_eq!(emulator.received(ProtocolID::ProtocolQueuedCmdLeftSpace), 1);
+
+        // The queue is full, so the next move waits for the left space polled again.
+        let (fourth, ()) = join(handle.insert_message(&move_to(), retry_policy()), async {
+            delay_for(WAIT_TIME).await;
+            assert!(emulator.received(ProtocolID::ProtocolQueuedCmdLeftSpace) > 2);
+            assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 3);
+            emulator.resume_queue();
+        })
+        .await;
+        assert_eq!(reply(fourth).queue_index(), 4);
+    }
+
+    #[tokio::test]
+    async fn queued_acks_are_matched_by_index_past_a_lost_one() {
+        let emulator = Emulator::new();
+        let (handle, mut replies) = start(&emulator, &[move_to()]);
+        assert_eq!(reply(replies.remove(0).await).queue_index(), 1);
+
+        emulator.inject(ProtocolID::ProtocolPTPCmd, Fault::DropReply);
+        let replies: Vec<_> = (0..3)
+            .map(|_| handle.insert_message(&move_to(), retry_policy()))
+            .collect();
+        let indices: Vec<_> = join_all(replies)
+            .await
+            .into_iter()
+            .map(|status| reply(status).queue_index())
+            .collect();
+        assert_eq!(indices, vec![2, 3, 4]);
+        assert_eq!(emulator.enqueued(ProtocolID::ProtocolPTPCmd), 4);
+    }
+
+    #[tokio::test]
+    async fn ack_is_matched_past_a_lost_reply_of_another_command() {
+        let emulator = Emulator::new();
+        emulator.inject(ProtocolID::ProtocolGetPose, Fault::DropReply);
+        let (_handle, replies) = start(&emulator, &[move_to(), get_pose(), move_to(), get_pose()]);
+
+        let mut replies = join_all(replies).await.into_iter().map(reply);
+        assert_eq!(replies.next().unwrap().queue_index(), 1);
+        let first = replies.next().unwrap();
+        assert_eq!(replies.next().unwrap().queue_index(), 2);
+        let second = replies.next().unwrap();
+        // The first pose was read again after the second one.
+        assert_eq!((pose_x(&first), pose_x(&second)), (3.0, 2.0));
     }
 }
@@ -1,15 +1,26 @@
 use crate::protocol::packet::{Packet, MAX_PACKET_SIZE};
+use crate::runtime::{timeout, Runtime};
+use async_io::Async;
+use futures::future::{BoxFuture, FutureExt};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
 use serialport::posix::TTYPort;
 use serialport::{DataBits, Error, FlowControl, Parity, SerialPortSettings, StopBits};
-use std::time::Duration;
-use tokio::time::{delay_for, timeout};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::path::Path;
 
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
 pub struct Connector {
-    io_device: TTYPort,
+    port_name: String,
+    settings: SerialPortSettings,
+    io_device: Option<Async<File>>,
     red_bytes: Vec<u8>,
+    runtime: Arc<dyn Runtime>,
 }
 
 #[derive(Debug)]
@@ -19,12 +30,26 @@ pub enum ConnectorError {
 
 type Result<T> = std::result::Result<T, ConnectorError>;
 
+/// The byte stream the `Communicator` talks to the controller over.
+pub trait Transport: Send {
+    /// Resolves to `Ok(None)` when no complete packet arrived within `wait_duration`.
+    fn read_packet_with_timeout(
+        &mut self,
+        wait_duration: Duration,
+    ) -> BoxFuture<'_, std::io::Result<Option<Packet>>>;
+
+    fn write_packet<'a>(&'a mut self, packet: &'a Packet) -> BoxFuture<'a, std::io::Result<usize>>;
+
+    fn reconnect(&mut self, wait_duration: Duration) -> BoxFuture<'_, Result<()>>;
+}
+
 impl Connector {
     pub fn connect(
         port_name: &str,
         boudrate: u32,
         _fw_type: Option<&str>,
         _version: Option<&str>,
+        runtime: Arc<dyn Runtime>,
     ) -> Result<Self> {
         // TODO(higumachan): UDP Connect and checking fw and version
 
@@ -37,38 +62,117 @@ impl Connector {
             flow_control: FlowControl::None,
         };
         Ok(Self {
-            io_device: TTYPort::open(&Path::new(port_name), &settings)
-                .map_err(|e| ConnectorError::SerialPortError(e))?,
+            port_name: port_name.to_string(),
+            settings,
+            io_device: Some(open(port_name, &settings).map_err(ConnectorError::SerialPortError)?),
             red_bytes: vec![],
+            runtime,
         })
     }
 
-    pub async fn read_packet(&mut self) -> Packet {
+    /// Closes the port and reopens it once the device has come back, e.g. after the controller
+    /// rebooted into another firmware.
+    pub async fn reconnect(&mut self, wait_duration: Duration) -> Result<()> {
+        self.io_device = None;
+        self.red_bytes.clear();
+
+        let deadline = Instant::now() + wait_duration;
+        loop {
+            self.runtime.delay(RECONNECT_INTERVAL).await;
+            match open(&self.port_name, &self.settings) {
+                Ok(io_device) => {
+                    self.io_device = Some(io_device);
+                    return Ok(());
+                }
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(ConnectorError::SerialPortError(e))
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    pub async fn read_packet(&mut self) -> std::io::Result<Packet> {
         loop {
+            if let Some(packet) = self.parse_packet() {
+                return Ok(packet);
+            }
+
             let mut buf = [0u8; MAX_PACKET_SIZE];
-            let size = self.io_device.read(&mut buf);
-            if size.is_err() {
-                delay_for(Duration::from_millis(10)).await;
-                continue;
+            let io_device = self
+                .io_device
+                .as_mut()
+                .ok_or_else(|| std::io::Error::from(ErrorKind::NotConnected))?;
+            // Resolves as soon as the reactor reports the port readable.
+            match io_device.read(&mut buf).await {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(size) => self.red_bytes.extend(buf[0..size].iter()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
             }
-            let size = size.unwrap();
-            self.red_bytes.extend(buf[0..size].iter());
-            let packet = Packet::from_bytes(&self.red_bytes);
-            if let Ok((remain, packet)) = packet {
-                self.red_bytes = Vec::from(remain);
-                return packet;
+        }
+    }
+
+    fn parse_packet(&mut self) -> Option<Packet> {
+        loop {
+            match Packet::from_bytes(&self.red_bytes) {
+                Ok((remain, packet)) => {
+                    self.red_bytes = Vec::from(remain);
+                    return Some(packet);
+                }
+                Err(nom::Err::Incomplete(_)) => return None,
+                // Line noise or a corrupted packet: skip a byte and look for the next sync bytes.
+                Err(_) => {
+                    self.red_bytes.remove(0);
+                }
             }
-            delay_for(Duration::from_millis(10)).await;
         }
     }
 
-    pub async fn read_packet_with_timeout(&mut self, wait_duration: Duration) -> Option<Packet> {
-        timeout(wait_duration, self.read_packet()).await.ok()
+    pub async fn read_packet_with_timeout(
+        &mut self,
+        wait_duration: Duration,
+    ) -> std::io::Result<Option<Packet>> {
+        let runtime = self.runtime.clone();
+        match timeout(&*runtime, wait_duration, self.read_packet()).await {
+            Some(packet) => packet.map(Some),
+            None => Ok(None),
+        }
     }
 
     pub async fn write_packet(&mut self, packet: &Packet) -> std::io::Result<usize> {
         let mut buf = [0u8; MAX_PACKET_SIZE];
-        packet.to_bytes(&mut buf)?;
-        self.io_device.write(&buf)
+        let size = packet.to_bytes(&mut buf)?;
+        let io_device = self
+            .io_device
+            .as_mut()
+            .ok_or_else(|| std::io::Error::from(ErrorKind::NotConnected))?;
+        io_device.write_all(&buf[..size]).await?;
+        Ok(size)
+    }
+}
+
+/// Opens the port and registers it with the async reactor, which puts it in non-blocking mode.
+fn open(port_name: &str, settings: &SerialPortSettings) -> serialport::Result<Async<File>> {
+    let port = TTYPort::open(Path::new(port_name), settings)?;
+    // `into_raw_fd` gives up the descriptor without closing it, so the `File` is its only owner.
+    let file = unsafe { File::from_raw_fd(port.into_raw_fd()) };
+    Ok(Async::new(file)?)
+}
+
+impl Transport for Connector {
+    fn read_packet_with_timeout(
+        &mut self,
+        wait_duration: Duration,
+    ) -> BoxFuture<'_, std::io::Result<Option<Packet>>> {
+        Connector::read_packet_with_timeout(self, wait_duration).boxed()
+    }
+
+    fn write_packet<'a>(&'a mut self, packet: &'a Packet) -> BoxFuture<'a, std::io::Result<usize>> {
+        Connector::write_packet(self, packet).boxed()
+    }
+
+    fn reconnect(&mut self, wait_duration: Duration) -> BoxFuture<'_, Result<()>> {
+        Connector::reconnect(self, wait_duration).boxed()
     }
 }
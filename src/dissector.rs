@@ -0,0 +1,470 @@
+//! Turns packets into something a person can read, for debugging a conversation with the
+//! firmware.
+//!
+//! `dissect` takes a single packet, `dissect_hex` a hex dump, e.g. copied from a serial sniffer,
+//! and `dissect_recording` a recording made with `DobotBuilder::record`. The `dobot-dissect`
+//! binary prints either kind of file.
+
+use crate::api::types::{
+    AlarmsState, AngleSensorCoef, AngleSensorStaticError, DeviceVersion, EndEffectorParams,
+    EndEffectorSuctionCapParams, FirmwareMode, Kinematics, PTPCmd, PTPCommonParams, Pose,
+    ResetPoseParams,
+};
+use crate::protocol::message::{FromParams, PARAMS_SIZE};
+use crate::protocol::packet::Packet;
+use crate::recording::{read_records, to_hex, MAGIC};
+use std::convert::TryFrom;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{ErrorKind, Read};
+use std::time::Duration;
+
+pub use crate::protocol::protocol_id::ProtocolID;
+pub use crate::recording::Direction;
+
+const PTP_MODES: [&str; 10] = [
+    "JUMP_XYZ",
+    "MOVJ_XYZ",
+    "MOVL_XYZ",
+    "JUMP_ANGLE",
+    "MOVJ_ANGLE",
+    "MOVL_ANGLE",
+    "MOVJ_INC",
+    "MOVL_INC",
+    "MOVJ_XYZ_INC",
+    "JUMP_MOVL_XYZ",
+];
+
+const FIRMWARE_SWITCHES: [&str; 8] = [
+    "NoSwitch", "Dobot", "Printing", "Driver1", "Driver2", "Driver3", "Driver4", "Driver5",
+];
+
+/// One packet, taken apart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dissection {
+    /// The time since the start of the recording, if the packet came from one.
+    pub time: Option<Duration>,
+    /// Unknown for packets from a hex dump.
+    pub direction: Option<Direction>,
+    pub id: u8,
+    /// `None` for ids the protocol does not define.
+    pub protocol: Option<ProtocolID>,
+    pub write: bool,
+    pub queued: bool,
+    pub params: Vec<u8>,
+    /// The params as the type the protocol carries, if known and the length fits, e.g.
+    /// `PTPCmd { mode: MOVL_XYZ, x: 200.0, y: 0.0, z: 50.0, r: 0.0 }`.
+    pub decoded: Option<String>,
+}
+
+impl Dissection {
+    fn new(packet: &Packet, direction: Option<Direction>, time: Option<Duration>) -> Self {
+        let message = packet.to_message();
+        let params = message.params[..message.params_len as usize].to_vec();
+        let protocol = ProtocolID::try_from(message.id).ok();
+        let write = message.rw == 1;
+        let queued = message.is_queued == 1;
+
+        let sides: &[Side] = match direction {
+            Some(Direction::Sent) => &[Side::Request],
+            Some(Direction::Received) => &[Side::Reply],
+            None => &[Side::Request, Side::Reply],
+        };
+        let decoded = protocol.and_then(|protocol| {
+            sides
+                .iter()
+                .filter_map(|side| decoder(protocol, write, queued, *side))
+                .find(|(size, _)| size.map_or(true, |size| size == params.len()))
+                .filter(|_| !params.is_empty())
+                .map(|(_, decode)| decode(&params))
+        });
+
+        Self {
+            time,
+            direction,
+            id: message.id,
+            protocol,
+            write,
+            queued,
+            params,
+            decoded,
+        }
+    }
+}
+
+impl Display for Dissection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(time) = self.time {
+            write!(f, "{:>12} ", format!("{:.3?}", time))?;
+        }
+        match self.direction {
+            Some(Direction::Sent) => f.write_str("-> ")?,
+            Some(Direction::Received) => f.write_str("<- ")?,
+            None => {}
+        }
+        let name = match self.protocol {
+            Some(protocol) => protocol.to_string(),
+            None => format!("Unknown({})", self.id),
+        };
+        write!(
+            f,
+            "{:<28} {:<5} {:<6}",
+            name,
+            if self.write { "write" } else { "read" },
+            if self.queued { "queued" } else { "" }
+        )?;
+        match &self.decoded {
+            Some(decoded) => write!(f, " {}", decoded),
+            None if self.params.is_empty() => Ok(()),
+            None => write!(f, " [{}]", to_hex(&self.params)),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Side {
+    Request,
+    Reply,
+}
+
+/// The length the params of a packet have, `None` if it varies, and how to show them.
+type Decoder = (Option<usize>, fn(&[u8]) -> String);
+
+fn decoder(protocol: ProtocolID, write: bool, queued: bool, side: Side) -> Option<Decoder> {
+    use ProtocolID::*;
+    use Side::*;
+
+    if queued {
+        return match side {
+            Request => decoder(protocol, write, false, side),
+            Reply => Some((Some(8), queue_index)),
+        };
+    }
+    Some(match (protocol, write, side) {
+        (ProtocolDeviceSN, false, Reply) => (None, typed::<String>),
+        (ProtocolDeviceName, true, Request) | (ProtocolDeviceName, false, Reply) => {
+            (None, typed::<String>)
+        }
+        (ProtocolDeviceVersion, false, Reply) => (Some(3), typed::<DeviceVersion>),
+        (ProtocolGetPose, false, Reply) => (Some(32), typed::<Pose>),
+        (ProtocolResetPose, true, Request) => (Some(9), typed::<ResetPoseParams>),
+        (ProtocolGetKinematics, false, Reply) => (Some(8), typed::<Kinematics>),
+        (ProtocolAlarmsState, false, Reply) => (Some(16), alarms),
+        (ProtocolHHTTrigMode, true, Request) => (Some(1), hht_trig_mode),
+        (ProtocolHHTTrigOutputEnabled, true, Request)
+        | (ProtocolHHTTrigOutputEnabled, false, Reply)
+        | (ProtocolHHTTrigOutput, false, Reply) => (Some(1), typed::<bool>),
+        (ProtocolEndEffectorParams, true, Request) | (ProtocolEndEffectorParams, false, Reply) => {
+            (Some(12), typed::<EndEffectorParams>)
+        }
+        (ProtocolEndEffectorSuctionCup, true, Request)
+        | (ProtocolEndEffectorSuctionCup, false, Reply) => {
+            (Some(2), typed::<EndEffectorSuctionCapParams>)
+        }
+        (ProtocolPTPCommonParams, true, Request) | (ProtocolPTPCommonParams, false, Reply) => {
+            (Some(8), typed::<PTPCommonParams>)
+        }
+        (ProtocolPTPCmd, true, Request) => (Some(17), ptp_cmd),
+        (ProtocolWAITCmd, true, Request) => (Some(4), wait_ms),
+        (ProtocolIODI, false, Request) => (Some(1), io_address),
+        (ProtocolIODI, false, Reply) => (Some(2), io_level),
+        (ProtocolAngleSensorStaticError, true, Request)
+        | (ProtocolAngleSensorStaticError, false, Reply) => {
+            (Some(8), typed::<AngleSensorStaticError>)
+        }
+        (ProtocolAngleSensorCoef, true, Request) | (ProtocolAngleSensorCoef, false, Reply) => {
+            (Some(8), typed::<AngleSensorCoef>)
+        }
+        (ProtocolBaseDecoderStaticError, true, Request)
+        | (ProtocolBaseDecoderStaticError, false, Reply) => (Some(4), typed::<f32>),
+        (ProtocolFirmwareSwitch, true, Request) => (Some(1), firmware_switch),
+        (ProtocolFirmwareMode, false, Reply) => (Some(1), typed::<FirmwareMode>),
+        (ProtocolLostStepSet, true, Request) => (Some(4), typed::<f32>),
+        (ProtocolQueuedCmdCurrentIndex, false, Reply) => (Some(8), queue_index),
+        (ProtocolQueuedCmdLeftSpace, false, Reply) => (Some(4), typed::<u32>),
+        _ => return None,
+    })
+}
+
+fn from_slice<T: FromParams>(params: &[u8]) -> T {
+    let mut buf = [0u8; PARAMS_SIZE];
+    buf[..params.len()].copy_from_slice(params);
+    T::from_params(params.len(), buf)
+}
+
+fn typed<T: FromParams + Debug>(params: &[u8]) -> String {
+    format!("{:?}", from_slice::<T>(params))
+}
+
+fn ptp_cmd(params: &[u8]) -> String {
+    let cmd = from_slice::<PTPCmd>(params);
+    let mode = match PTP_MODES.get(cmd.ptp_mode as usize) {
+        Some(mode) => mode.to_string(),
+        None => cmd.ptp_mode.to_string(),
+    };
+    format!(
+        "PTPCmd {{ mode: {}, x: {:?}, y: {:?}, z: {:?}, r: {:?} }}",
+        mode, cmd.x, cmd.y, cmd.z, cmd.r
+    )
+}
+
+fn alarms(params: &[u8]) -> String {
+    let state = from_slice::<AlarmsState>(params);
+    let alarms = (0..(state.bits.len() * 8) as u8)
+        .filter(|alarm| state.is_alarmed(*alarm))
+        .map(|alarm| format!("{:#04x}", alarm))
+        .collect::<Vec<_>>();
+    format!("AlarmsState [{}]", alarms.join(", "))
+}
+
+fn hht_trig_mode(params: &[u8]) -> String {
+    match params[0] {
+        0 => "TriggeredOnKeyReleased".to_string(),
+        1 => "TriggeredOnPeriodicInterval".to_string(),
+        mode => format!("HHTTrigMode({})", mode),
+    }
+}
+
+fn firmware_switch(params: &[u8]) -> String {
+    match FIRMWARE_SWITCHES.get(params[0] as usize) {
+        Some(switch) => switch.to_string(),
+        None => format!("FirmwareSwitch({})", params[0]),
+    }
+}
+
+fn wait_ms(params: &[u8]) -> String {
+    format!("{} ms", from_slice::<u32>(params))
+}
+
+fn queue_index(params: &[u8]) -> String {
+    format!("queue index {}", from_slice::<u64>(params))
+}
+
+fn io_address(params: &[u8]) -> String {
+    format!("address {}", params[0])
+}
+
+fn io_level(params: &[u8]) -> String {
+    format!(
+        "address {}: {}",
+        params[0],
+        if params[1] != 0 { "high" } else { "low" }
+    )
+}
+
+/// Dissects the packet `bytes` hold, failing if they are not exactly one.
+pub fn dissect(bytes: &[u8], direction: Option<Direction>) -> std::io::Result<Dissection> {
+    match Packet::from_bytes(bytes) {
+        Ok(([], packet)) => Ok(Dissection::new(&packet, direction, None)),
+        _ => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not a packet", to_hex(bytes)),
+        )),
+    }
+}
+
+/// Dissects every packet in a hex dump such as `aa aa 02 0a 00 f6`, `AAAA020A00F6` or
+/// `0xaa, 0xaa, ...`, as well as the output of `hexdump -C`, whose offsets and `|...|`
+/// columns are stripped. Bytes that do not belong to a valid packet, e.g. line noise, are
+/// skipped.
+pub fn dissect_hex(text: &str) -> std::io::Result<Vec<Dissection>> {
+    let hexdump = text.lines().any(|line| line.contains('|'));
+    let text = text
+        .lines()
+        .map(|line| match hexdump {
+            true => strip_hexdump_columns(line),
+            false => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut bytes = Vec::new();
+    for word in text
+        .replace("0x", " ")
+        .replace("0X", " ")
+        .split(|c: char| !c.is_ascii_hexdigit())
+        .filter(|word| !word.is_empty())
+    {
+        if word.len() % 2 != 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a whole number of bytes", word),
+            ));
+        }
+        for i in (0..word.len()).step_by(2) {
+            bytes.push(u8::from_str_radix(&word[i..i + 2], 16).unwrap());
+        }
+    }
+
+    let mut dissections = Vec::new();
+    let mut input = &bytes[..];
+    while !input.is_empty() {
+        match Packet::from_bytes(input) {
+            Ok((remain, packet)) => {
+                dissections.push(Dissection::new(&packet, None, None));
+                input = remain;
+            }
+            Err(_) => input = &input[1..],
+        }
+    }
+    Ok(dissections)
+}
+
+/// Drops the leading offset and the trailing `|...|` ASCII column of a `hexdump -C` line,
+/// e.g. `00000000  aa aa 02 0a 00 f6  |......|`, leaving only the bytes.
+fn strip_hexdump_columns(line: &str) -> &str {
+    let line = line.split('|').next().unwrap_or_default();
+    match line.split_whitespace().next() {
+        Some(offset)
+            if line.starts_with(offset)
+                && offset.len() == 8
+                && offset.chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            &line[offset.len()..]
+        }
+        _ => line,
+    }
+}
+
+/// Dissects every packet of a recording in either `RecordFormat`.
+pub fn dissect_recording(source: impl Read) -> std::io::Result<Vec<Dissection>> {
+    read_records(source)?
+        .into_iter()
+        .map(|record| {
+            let mut dissection = dissect(&record.bytes, Some(record.direction))?;
+            dissection.time = Some(record.time);
+            Ok(dissection)
+        })
+        .collect()
+}
+
+/// Dissects a recording or, failing to recognise one by its first bytes, a hex dump.
+pub fn dissect_any(input: &[u8]) -> std::io::Result<Vec<Dissection>> {
+    let text = String::from_utf8_lossy(input);
+    if input.starts_with(MAGIC) || text.trim_start().starts_with('{') {
+        dissect_recording(input)
+    } else {
+        dissect_hex(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Dobot;
+    use crate::emulator::{Emulator, SharedSink};
+    use crate::protocol::message::{Message, ReadWrite};
+    use crate::recording::{RecordFormat, Recorder};
+
+    fn to_bytes(message: &Message) -> Vec<u8> {
+        let mut buf = [0u8; 256];
+        let size = Packet::from_message(message).to_bytes(&mut buf).unwrap();
+        buf[..size].to_vec()
+    }
+
+    #[test]
+    fn protocol_ids_round_trip() {
+        for protocol in ProtocolID::ALL.iter() {
+            assert_eq!(ProtocolID::try_from(*protocol as u8), Ok(*protocol));
+        }
+        assert_eq!(ProtocolID::try_from(255), Err(255));
+        assert_eq!(ProtocolID::ProtocolPTPCmd.to_string(), "PTPCmd");
+    }
+
+    #[test]
+    fn ptp_command_is_decoded_from_a_hex_dump() {
+        let cmd = PTPCmd {
+            ptp_mode: 2,
+            x: 200.0,
+            y: 0.0,
+            z: 50.0,
+            r: 0.0,
+        };
+        let request = Message::new(
+            ProtocolID::ProtocolPTPCmd,
+            ReadWrite::Write,
+            true,
+            &Some(cmd),
+        );
+        let dump = format!(
+            "00000000  ff {}\n{}",
+            to_hex(&to_bytes(&request)),
+            to_hex(&to_bytes(&request.new_queue_ack(7)))
+                .chars()
+                .collect::<Vec<_>>()
+                .chunks(2)
+                .map(|byte| format!("0x{}", byte.iter().collect::<String>()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let dissections = dissect_hex(&dump).unwrap();
+
+        assert_eq!(dissections.len(), 2);
+        assert_eq!(dissections[0].protocol, Some(ProtocolID::ProtocolPTPCmd));
+        assert!(dissections[0].write && dissections[0].queued);
+        assert_eq!(
+            dissections[0].decoded.as_deref(),
+            Some("PTPCmd { mode: MOVL_XYZ, x: 200.0, y: 0.0, z: 50.0, r: 0.0 }")
+        );
+        assert_eq!(dissections[1].decoded.as_deref(), Some("queue index 7"));
+    }
+
+    #[test]
+    fn unknown_ids_and_odd_lengths_are_shown_raw() {
+        let unknown = dissect(&[0xaa, 0xaa, 0x03, 0xff, 0x00, 0x01, 0x00], None).unwrap();
+        assert_eq!(unknown.protocol, None);
+        assert!(unknown.to_string().starts_with("Unknown(255)"));
+        assert!(unknown.to_string().ends_with("[01]"));
+
+        let short_pose = dissect(&[0xaa, 0xaa, 0x03, 0x0a, 0x00, 0x01, 0xf5], None).unwrap();
+        assert_eq!(short_pose.decoded, None);
+
+        assert!(dissect_hex("aa a").is_err());
+    }
+
+    #[test]
+    fn hexdump_offsets_and_ascii_columns_are_stripped() {
+        let dump = "\
+00000000  61 7c 62 aa aa 02 0a 00  f6 aa aa 02 0a 00 f6 aa  |a|b.............|
+00000010  aa 02 0a 00 f6                                    |.....|
+00000015
+";
+
+        let dissections = dissect_hex(dump).unwrap();
+        assert_eq!(dissections.len(), 3);
+        for dissection in dissections.iter() {
+            assert_eq!(dissection.protocol, Some(ProtocolID::ProtocolGetPose));
+        }
+    }
+
+    #[tokio::test]
+    async fn recordings_are_dissected_in_either_format() {
+        for format in RecordFormat::ALL.iter() {
+            let sink = SharedSink::default();
+            let emulator = Emulator::new();
+            emulator.alarm(0x51);
+            let dobot = Dobot::builder()
+                .transport(Box::new(
+                    Recorder::new(Box::new(emulator), Box::new(sink.clone()), *format).unwrap(),
+                ))
+                .connect()
+                .await
+                .unwrap();
+            dobot.get_alarms_state().await.unwrap();
+            dobot.disconnect_dobot().await;
+
+            let recording = sink.contents();
+            let dissections = dissect_any(&recording).unwrap();
+
+            assert_eq!(dissections.len(), 2);
+            assert_eq!(dissections[0].direction, Some(Direction::Sent));
+            assert_eq!(dissections[1].direction, Some(Direction::Received));
+            assert!(dissections[1].time.is_some());
+            assert_eq!(
+                dissections[1].decoded.as_deref(),
+                Some("AlarmsState [0x51]")
+            );
+            assert!(dissections[1].to_string().contains("<- AlarmsState"));
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{BoxFuture, FutureExt};
+
+use crate::connector::{ConnectorError, Transport};
+use crate::protocol::message::{Message, PARAMS_SIZE};
+use crate::protocol::packet::Packet;
+use crate::protocol::protocol_id::ProtocolID;
+use crate::runtime::{default_runtime, Runtime};
+
+pub const SERIAL_NUMBER: &str = "EMU0001";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    WriteError,
+    ReadError,
+    DropReply,
+    /// The command never reaches the controller.
+    DropRequest,
+    /// The reply only shows up after the communicator gave up waiting for it.
+    LateReply,
+}
+
+struct State {
+    queue_index: u64,
+    // The index the controller stopped executing at, if it did.
+    held_at: Option<u64>,
+    enqueued: Vec<u8>,
+    // The id of every request that reached the controller.
+    received: Vec<u8>,
+    // The most replies that were waiting to be read at once.
+    max_unread: usize,
+    poses: u32,
+    alarms: [u8; 16],
+    suction_cup: [u8; 2],
+    digital_inputs: Vec<u8>,
+    device_name: Vec<u8>,
+    firmware_mode: u8,
+    queue_capacity: u32,
+    faults: Vec<(u8, Fault)>,
+    outbox: VecDeque<std::io::Result<Packet>>,
+    late: Option<Packet>,
+}
+
+/// A minimal in-process controller: every command is acknowledged immediately and every queued
+/// command is executed as soon as it is accepted. Faults can be injected per protocol id.
+#[derive(Clone)]
+pub struct Emulator {
+    state: Arc<Mutex<State>>,
+    runtime: Arc<dyn Runtime>,
+}
+
+impl Emulator {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                queue_index: 0,
+                held_at: None,
+                enqueued: vec![],
+                received: vec![],
+                max_unread: 0,
+                poses: 0,
+                alarms: [0; 16],
+                suction_cup: [0; 2],
+                digital_inputs: vec![],
+                device_name: vec![],
+                firmware_mode: 1,
+                queue_capacity: 32,
+                faults: vec![],
+                outbox: VecDeque::new(),
+                late: None,
+            })),
+            runtime: default_runtime().unwrap(),
+        }
+    }
+
+    /// Fails the next exchange of `id` with `fault`.
+    pub fn inject(&self, id: ProtocolID, fault: Fault) {
+        self.state.lock().unwrap().faults.push((id as u8, fault));
+    }
+
+    /// Stops executing queued commands, which are still accepted.
+    pub fn hold_queue(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.held_at = Some(state.queue_index);
+    }
+
+    /// Executes the held commands, and those queued since.
+    pub fn resume_queue(&self) {
+        self.state.lock().unwrap().held_at = None;
+    }
+
+    /// Makes room for `capacity` commands in the queue. Held commands take up room until the
+    /// queue is resumed.
+    pub fn set_queue_capacity(&self, capacity: u32) {
+        self.state.lock().unwrap().queue_capacity = capacity;
+    }
+
+    /// How many requests with `id` reached the controller.
+    pub fn received(&self, id: ProtocolID) -> usize {
+        let id = id as u8;
+        let state = self.state.lock().unwrap();
+        state.received.iter().filter(|i| **i == id).count()
+    }
+
+    /// The most replies that were waiting to be read at once, i.e. how many commands were in
+    /// flight together.
+    pub fn max_unread(&self) -> usize {
+        self.state.lock().unwrap().max_unread
+    }
+
+    /// Executes the held commands and raises `alarm` at once, as a lost step check that finds
+    /// the arm off its position does.
+    pub fn resume_queue_raising(&self, alarm: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.held_at = None;
+        state.alarms[(alarm / 8) as usize] |= 1 << (alarm % 8);
+    }
+
+    /// Raises `alarm` until the alarms are cleared.
+    pub fn alarm(&self, alarm: u8) {
+        self.state.lock().unwrap().alarms[(alarm / 8) as usize] |= 1 << (alarm % 8);
+    }
+
+    /// Pulls the EIO input at `address` high.
+    pub fn set_digital_input(&self, address: u8) {
+        self.state.lock().unwrap().digital_inputs.push(address);
+    }
+
+    /// Whether a communicator still holds a clone of this emulator.
+    pub fn is_connected(&self) -> bool {
+        Arc::strong_count(&self.state) > 1
+    }
+
+    /// How many times a queued `id` was enqueued.
+    pub fn enqueued(&self, id: ProtocolID) -> usize {
+        let id = id as u8;
+        let state = self.state.lock().unwrap();
+        state.enqueued.iter().filter(|i| **i == id).count()
+    }
+
+    fn take_fault(state: &mut State, id: u8) -> Option<Fault> {
+        let position = state.faults.iter().position(|(i, _)| *i == id)?;
+        Some(state.faults.remove(position).1)
+    }
+
+    fn reply(state: &mut State, message: &Message) -> Message {
+        state.received.push(message.id);
+        let mut params = [0u8; PARAMS_SIZE];
+        let params_len = if message.is_queued != 0 {
+            state.queue_index += 1;
+            state.enqueued.push(message.id);
+            if message.id == ProtocolID::ProtocolEndEffectorSuctionCup as u8 {
+                state.suction_cup.copy_from_slice(&message.params[..2]);
+            }
+            params[..8].copy_from_slice(&state.queue_index.to_le_bytes());
+            8
+        } else if message.id == ProtocolID::ProtocolQueuedCmdCurrentIndex as u8 {
+            let executed = state.held_at.unwrap_or(state.queue_index);
+            params[..8].copy_from_slice(&executed.to_le_bytes());
+            8
+        } else if message.id == ProtocolID::ProtocolQueuedCmdLeftSpace as u8 {
+            let held = state.queue_index - state.held_at.unwrap_or(state.queue_index);
+            let left_space = state.queue_capacity.saturating_sub(held as u32);
+            params[..4].copy_from_slice(&left_space.to_le_bytes());
+            4
+        } else if message.id == ProtocolID::ProtocolAlarmsState as u8 && message.rw == 0 {
+            params[..16].copy_from_slice(&state.alarms);
+            16
+        } else if message.id == ProtocolID::ProtocolAlarmsState as u8 {
+            state.alarms = [0; 16];
+            0
+        } else if message.id == ProtocolID::ProtocolDeviceSN as u8 {
+            params[..SERIAL_NUMBER.len()].copy_from_slice(SERIAL_NUMBER.as_bytes());
+            SERIAL_NUMBER.len() as u8
+        } else if message.id == ProtocolID::ProtocolDeviceName as u8 && message.rw == 0 {
+            let name = &state.device_name;
+            params[..name.len()].copy_from_slice(name);
+            name.len() as u8
+        } else if message.id == ProtocolID::ProtocolDeviceName as u8 {
+            state.device_name = message.params[..message.params_len as usize].to_vec();
+            0
+        } else if message.id == ProtocolID::ProtocolEndEffectorSuctionCup as u8 && message.rw == 0 {
+            params[..2].copy_from_slice(&state.suction_cup);
+            2
+        } else if message.id == ProtocolID::ProtocolEndEffectorSuctionCup as u8 {
+            state.suction_cup.copy_from_slice(&message.params[..2]);
+            0
+        } else if message.id == ProtocolID::ProtocolIODI as u8 {
+            let address = message.params[0];
+            params[0] = address;
+            params[1] = state.digital_inputs.contains(&address) as u8;
+            2
+        } else if message.id == ProtocolID::ProtocolFirmwareSwitch as u8 {
+            // Dobot and printing firmware; the drivers leave the mode alone.
+            if let switch @ 1..=2 = message.params[0] {
+                state.firmware_mode = switch;
+            }
+            0
+        } else if message.id == ProtocolID::ProtocolFirmwareMode as u8 {
+            params[0] = state.firmware_mode;
+            1
+        } else if message.id == ProtocolID::ProtocolGetPose as u8 {
+            // Number the poses so tests can tell replies apart.
+            state.poses += 1;
+            params[..4].copy_from_slice(&(state.poses as f32).to_le_bytes());
+            32
+        } else {
+            0
+        };
+
+        Message {
+            id: message.id,
+            rw: message.rw,
+            is_queued: message.is_queued,
+            params_len,
+            params,
+        }
+    }
+}
+
+/// A recording sink the test can read back once the recorder is done with it.
+#[derive(Clone, Default)]
+pub struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+impl SharedSink {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Emulator {
+    fn read_packet_with_timeout(
+        &mut self,
+        wait_duration: Duration,
+    ) -> BoxFuture<'_, std::io::Result<Option<Packet>>> {
+        async move {
+            let packet = self.state.lock().unwrap().outbox.pop_front();
+            match packet {
+                Some(packet) => packet.map(Some),
+                None => {
+                    self.runtime.delay(wait_duration).await;
+                    let mut state = self.state.lock().unwrap();
+                    if let Some(late) = state.late.take() {
+                        state.outbox.push_back(Ok(late));
+                    }
+                    Ok(None)
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn write_packet<'a>(&'a mut self, packet: &'a Packet) -> BoxFuture<'a, std::io::Result<usize>> {
+        async move {
+            let message = packet.to_message();
+            let mut state = self.state.lock().unwrap();
+            match Self::take_fault(&mut state, message.id) {
+                Some(Fault::WriteError) => {
+                    return Err(std::io::ErrorKind::BrokenPipe.into());
+                }
+                Some(Fault::ReadError) => {
+                    Self::reply(&mut state, &message);
+                    state
+                        .outbox
+                        .push_back(Err(std::io::ErrorKind::BrokenPipe.into()));
+                }
+                Some(Fault::DropRequest) => {}
+                Some(Fault::DropReply) => {
+                    Self::reply(&mut state, &message);
+                }
+                Some(Fault::LateReply) => {
+                    let reply = Self::reply(&mut state, &message);
+                    state.late = Some(Packet::from_message(&reply));
+                }
+                None => {
+                    let reply = Self::reply(&mut state, &message);
+                    state.outbox.push_back(Ok(Packet::from_message(&reply)));
+                    state.max_unread = state.max_unread.max(state.outbox.len());
+                }
+            }
+            Ok(message.params_len as usize + 6)
+        }
+        .boxed()
+    }
+
+    fn reconnect(&mut self, _wait_duration: Duration) -> BoxFuture<'_, Result<(), ConnectorError>> {
+        async move {
+            self.state.lock().unwrap().outbox.clear();
+            Ok(())
+        }
+        .boxed()
+    }
+}
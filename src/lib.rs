@@ -1,7 +1,14 @@
 pub mod api;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 mod communicator;
 mod connector;
+pub mod dissector;
+#[cfg(test)]
+mod emulator;
 mod protocol;
+pub mod recording;
+pub mod runtime;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,323 @@
+use crate::protocol::message::Message;
+use crate::protocol::protocol_id::ProtocolID;
+use std::convert::TryFrom;
+
+/// What the protocol allows for one id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Descriptor {
+    /// The id's name without the `Protocol` prefix, e.g. `PTPCmd`.
+    pub name: &'static str,
+    pub readable: bool,
+    pub writable: bool,
+    /// Whether a write may go into the controller's command queue.
+    pub queueable: bool,
+    /// Length of the params of a write, `None` if it varies or is not documented.
+    pub params_size: Option<usize>,
+    /// Length of the params of the reply to a read, `None` if it varies or is not documented.
+    pub response_size: Option<usize>,
+}
+
+/// Why a message must not be sent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Violation {
+    UnknownId(u8),
+    NotReadable(ProtocolID),
+    NotWritable(ProtocolID),
+    NotQueueable(ProtocolID),
+    ParamsSize {
+        id: ProtocolID,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+const NONE: Descriptor = Descriptor {
+    name: "",
+    readable: false,
+    writable: false,
+    queueable: false,
+    params_size: None,
+    response_size: None,
+};
+
+const fn read(response_size: Option<usize>) -> Descriptor {
+    Descriptor {
+        readable: true,
+        response_size,
+        ..NONE
+    }
+}
+
+const fn write(params_size: Option<usize>) -> Descriptor {
+    Descriptor {
+        writable: true,
+        params_size,
+        ..NONE
+    }
+}
+
+const fn queued(params_size: Option<usize>) -> Descriptor {
+    Descriptor {
+        queueable: true,
+        ..write(params_size)
+    }
+}
+
+/// A setting read and written as the same type.
+const fn setting(size: Option<usize>) -> Descriptor {
+    Descriptor {
+        readable: true,
+        response_size: size,
+        ..write(size)
+    }
+}
+
+const fn queued_setting(size: Option<usize>) -> Descriptor {
+    Descriptor {
+        queueable: true,
+        ..setting(size)
+    }
+}
+
+macro_rules! descriptors {
+    ($($id:ident => $descriptor:expr,)*) => {
+        impl ProtocolID {
+            pub fn descriptor(self) -> Descriptor {
+                match self {
+                    $(ProtocolID::$id => Descriptor {
+                        name: &stringify!($id)["Protocol".len()..],
+                        ..$descriptor
+                    },)*
+                }
+            }
+        }
+    };
+}
+
+descriptors! {
+    ProtocolDeviceSN => setting(None),
+    ProtocolDeviceName => setting(None),
+    ProtocolDeviceVersion => read(Some(3)),
+    ProtocolDeviceWithL => queued_setting(None),
+    ProtocolDeviceTime => read(Some(4)),
+
+    ProtocolGetPose => read(Some(32)),
+    ProtocolResetPose => write(Some(9)),
+    ProtocolGetKinematics => read(Some(8)),
+    ProtocolGetPoseL => read(Some(4)),
+
+    // Written without params to clear every alarm.
+    ProtocolAlarmsState => Descriptor { writable: true, params_size: Some(0), ..read(Some(16)) },
+
+    ProtocolHOMEParams => queued_setting(Some(16)),
+    ProtocolHOMECmd => queued(Some(4)),
+    ProtocolAutoLeveling => Descriptor { response_size: Some(4), ..queued_setting(Some(5)) },
+
+    ProtocolHHTTrigMode => setting(Some(1)),
+    ProtocolHHTTrigOutputEnabled => setting(Some(1)),
+    ProtocolHHTTrigOutput => read(Some(1)),
+
+    ProtocolArmOrientation => queued_setting(Some(1)),
+
+    ProtocolEndEffectorParams => queued_setting(Some(12)),
+    ProtocolEndEffectorLaser => queued_setting(Some(2)),
+    ProtocolEndEffectorSuctionCup => queued_setting(Some(2)),
+    ProtocolEndEffectorGripper => queued_setting(Some(2)),
+
+    ProtocolJOGJointParams => queued_setting(Some(32)),
+    ProtocolJOGCoordinateParams => queued_setting(Some(32)),
+    ProtocolJOGCommonParams => queued_setting(Some(8)),
+    ProtocolJOGCmd => queued(Some(2)),
+    ProtocolJOGLParams => queued_setting(Some(8)),
+
+    ProtocolPTPJointParams => queued_setting(Some(32)),
+    ProtocolPTPCoordinateParams => queued_setting(Some(16)),
+    ProtocolPTPJumpParams => queued_setting(Some(8)),
+    ProtocolPTPCommonParams => queued_setting(Some(8)),
+    ProtocolPTPCmd => queued(Some(17)),
+    ProtocolPTPLParams => queued_setting(Some(8)),
+    ProtocolPTPWithLCmd => queued(Some(21)),
+    ProtocolPTPJump2Params => queued_setting(Some(12)),
+    ProtocolPTPPOCmd => queued(None),
+    ProtocolPTPPOWithLCmd => queued(None),
+
+    ProtocolCPParams => queued_setting(Some(13)),
+    ProtocolCPCmd => queued(Some(17)),
+    ProtocolCPLECmd => queued(Some(17)),
+    ProtocolCPRHoldEnable => queued_setting(Some(1)),
+    ProtocolCPCommonParams => queued_setting(Some(8)),
+
+    ProtocolARCParams => queued_setting(Some(16)),
+    ProtocolARCCmd => queued(Some(32)),
+    ProtocolCircleCmd => queued(Some(36)),
+    ProtocolARCCommonParams => queued_setting(Some(8)),
+
+    ProtocolWAITCmd => queued(Some(4)),
+
+    ProtocolTRIGCmd => queued(Some(5)),
+
+    ProtocolIOMultiplexing => queued_setting(Some(2)),
+    ProtocolIODO => queued_setting(Some(2)),
+    ProtocolIOPWM => queued_setting(Some(9)),
+    ProtocolIODI => read(Some(2)),
+    ProtocolIOADC => read(Some(3)),
+    ProtocolEMotor => queued(Some(6)),
+    ProtocolEMotorS => queued(Some(10)),
+    ProtocolColorSensor => Descriptor { response_size: None, ..queued_setting(Some(3)) },
+    ProtocolIRSwitch => Descriptor { response_size: None, ..queued_setting(Some(3)) },
+
+    ProtocolAngleSensorStaticError => setting(Some(8)),
+    ProtocolAngleSensorCoef => setting(Some(8)),
+    ProtocolBaseDecoderStaticError => setting(Some(4)),
+    ProtocolLRHandCalibrateValue => setting(Some(4)),
+
+    ProtocolWIFIConfigMode => setting(Some(1)),
+    ProtocolWIFISSID => setting(None),
+    ProtocolWIFIPassword => setting(None),
+    ProtocolWIFIIPAddress => setting(Some(5)),
+    ProtocolWIFINetmask => setting(Some(4)),
+    ProtocolWIFIGateway => setting(Some(4)),
+    ProtocolWIFIDNS => setting(Some(4)),
+    ProtocolWIFIConnectStatus => read(Some(1)),
+
+    ProtocolFirmwareSwitch => write(Some(1)),
+    ProtocolFirmwareMode => read(None),
+
+    ProtocolLostStepSet => write(Some(4)),
+    ProtocolLostStepDetect => queued(Some(0)),
+
+    ProtocolCheckUART4PeripheralsModel => read(Some(1)),
+    ProtocolUART4PeripheralsEnabled => setting(Some(1)),
+
+    ProtocolFunctionPulseMode => setting(Some(1)),
+
+    ProtocolUserParams => setting(None),
+    // Read with the `PTPCmd` to estimate as params.
+    ProtocolPTPTime => read(Some(4)),
+    ProtocolServoPIDParams => setting(Some(22)),
+    ProtocolServoControlLoop => write(Some(2)),
+    ProtocolSaveServoPIDParams => write(Some(2)),
+
+    ProtocolQueuedCmdStartExec => write(Some(0)),
+    ProtocolQueuedCmdStopExec => write(Some(0)),
+    ProtocolQueuedCmdForceStopExec => write(Some(0)),
+    ProtocolQueuedCmdStartDownload => write(Some(8)),
+    ProtocolQueuedCmdStopDownload => write(Some(0)),
+    ProtocolQueuedCmdClear => write(Some(0)),
+    ProtocolQueuedCmdCurrentIndex => read(Some(8)),
+    ProtocolQueuedCmdLeftSpace => read(Some(4)),
+}
+
+/// Checks `message` against the descriptor of its id. Only the params of writes are checked, as
+/// some reads carry params to select what to read.
+pub fn validate(message: &Message) -> Result<(), Violation> {
+    let id = ProtocolID::try_from(message.id).map_err(Violation::UnknownId)?;
+    let descriptor = id.descriptor();
+
+    if message.rw == 0 {
+        if !descriptor.readable {
+            return Err(Violation::NotReadable(id));
+        }
+        if message.is_queued != 0 {
+            return Err(Violation::NotQueueable(id));
+        }
+        return Ok(());
+    }
+
+    if !descriptor.writable {
+        return Err(Violation::NotWritable(id));
+    }
+    if message.is_queued != 0 && !descriptor.queueable {
+        return Err(Violation::NotQueueable(id));
+    }
+    match descriptor.params_size {
+        Some(expected) if expected != message.params_len as usize => Err(Violation::ParamsSize {
+            id,
+            expected,
+            actual: message.params_len as usize,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::types::{PTPCmd, ResetPoseParams};
+    use crate::protocol::message::ReadWrite;
+
+    #[test]
+    fn descriptors_are_consistent() {
+        for id in ProtocolID::ALL.iter() {
+            let descriptor = id.descriptor();
+            assert_eq!(descriptor.name, id.to_string());
+            assert!(descriptor.readable || descriptor.writable, "{}", id);
+            assert!(!descriptor.queueable || descriptor.writable, "{}", id);
+        }
+    }
+
+    #[test]
+    fn params_sizes_match_the_types_sent() {
+        let ptp_cmd = Message::new(
+            ProtocolID::ProtocolPTPCmd,
+            ReadWrite::Write,
+            true,
+            &Some(PTPCmd::default()),
+        );
+        let reset_pose = Message::new(
+            ProtocolID::ProtocolResetPose,
+            ReadWrite::Write,
+            false,
+            &Some(ResetPoseParams::default()),
+        );
+
+        assert_eq!(validate(&ptp_cmd), Ok(()));
+        assert_eq!(validate(&reset_pose), Ok(()));
+        assert_eq!(validate(&Message::new_get_queue_index()), Ok(()));
+        assert_eq!(validate(&Message::new_get_left_space()), Ok(()));
+    }
+
+    #[test]
+    fn illegal_messages_are_rejected() {
+        let queued_read =
+            Message::new::<()>(ProtocolID::ProtocolGetPose, ReadWrite::Read, true, &None);
+        let write_of_read_only =
+            Message::new::<()>(ProtocolID::ProtocolGetPose, ReadWrite::Write, false, &None);
+        let queued_setting = Message::new(
+            ProtocolID::ProtocolHHTTrigMode,
+            ReadWrite::Write,
+            true,
+            &Some(0u8),
+        );
+        let short_ptp_cmd = Message::new(
+            ProtocolID::ProtocolPTPCmd,
+            ReadWrite::Write,
+            true,
+            &Some(1.0f32),
+        );
+        let mut unknown = Message::new_get_left_space();
+        unknown.id = 255;
+
+        assert_eq!(
+            validate(&queued_read),
+            Err(Violation::NotQueueable(ProtocolID::ProtocolGetPose))
+        );
+        assert_eq!(
+            validate(&write_of_read_only),
+            Err(Violation::NotWritable(ProtocolID::ProtocolGetPose))
+        );
+        assert_eq!(
+            validate(&queued_setting),
+            Err(Violation::NotQueueable(ProtocolID::ProtocolHHTTrigMode))
+        );
+        assert_eq!(
+            validate(&short_ptp_cmd),
+            Err(Violation::ParamsSize {
+                id: ProtocolID::ProtocolPTPCmd,
+                expected: 17,
+                actual: 4,
+            })
+        );
+        assert_eq!(validate(&unknown), Err(Violation::UnknownId(255)));
+    }
+}
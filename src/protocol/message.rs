@@ -3,9 +3,10 @@ use crate::protocol::protocol_id::ProtocolID;
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use nom::lib::std::fmt::{Debug, Formatter};
+use std::convert::TryFrom;
 use std::io::{Cursor, Read, Write};
 
-const MAX_PAYLOAD_SIZE: u8 = (SYNC_BYTE - 1);
+const MAX_PAYLOAD_SIZE: u8 = SYNC_BYTE - 1;
 pub const PARAMS_SIZE: usize = MAX_PAYLOAD_SIZE as usize - 2;
 
 pub trait ToParams {
@@ -23,6 +24,13 @@ impl ToParamable for f32 {
     }
 }
 
+impl ToParamable for u32 {
+    fn to_params(&self, buf: &mut [u8]) -> usize {
+        let a = self.to_le_bytes();
+        a.as_ref().read(buf).unwrap()
+    }
+}
+
 impl ToParamable for u8 {
     fn to_params(&self, buf: &mut [u8]) -> usize {
         buf[0] = *self;
@@ -50,18 +58,78 @@ impl<T: ToParamable> ToParams for T {
     }
 }
 
+impl ToParams for String {
+    fn to_params(&self) -> std::io::Result<(usize, [u8; PARAMS_SIZE])> {
+        if self.len() > PARAMS_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("longer than {} bytes", PARAMS_SIZE),
+            ));
+        }
+        let mut b = [0u8; PARAMS_SIZE];
+        let size = self.as_bytes().read(&mut b)?;
+        Ok((size, b))
+    }
+}
+
 pub trait FromParams {
     fn from_params(size: usize, params: [u8; PARAMS_SIZE]) -> Self;
 }
 
-impl FromParams for u64 {
-    fn from_params(size: usize, params: [u8; PARAMS_SIZE]) -> Self {
-        let mut rdr = Cursor::new(&params);
+pub trait FromParamable {
+    fn from_params(rdr: &mut Cursor<&[u8]>) -> Self;
+}
 
+impl FromParamable for f32 {
+    fn from_params(rdr: &mut Cursor<&[u8]>) -> Self {
+        rdr.read_f32::<LittleEndian>().unwrap()
+    }
+}
+
+impl FromParamable for u8 {
+    fn from_params(rdr: &mut Cursor<&[u8]>) -> Self {
+        rdr.read_u8().unwrap()
+    }
+}
+
+impl FromParamable for bool {
+    fn from_params(rdr: &mut Cursor<&[u8]>) -> Self {
+        rdr.read_u8().unwrap() != 0
+    }
+}
+
+impl FromParamable for u32 {
+    fn from_params(rdr: &mut Cursor<&[u8]>) -> Self {
+        rdr.read_u32::<LittleEndian>().unwrap()
+    }
+}
+
+impl FromParamable for u64 {
+    fn from_params(rdr: &mut Cursor<&[u8]>) -> Self {
         rdr.read_u64::<LittleEndian>().unwrap()
     }
 }
 
+impl<T: FromParamable> FromParams for T {
+    fn from_params(_size: usize, params: [u8; PARAMS_SIZE]) -> Self {
+        let mut rdr = Cursor::new(&params[..]);
+
+        FromParamable::from_params(&mut rdr)
+    }
+}
+
+impl FromParams for () {
+    fn from_params(_size: usize, _params: [u8; PARAMS_SIZE]) -> Self {}
+}
+
+impl FromParams for String {
+    fn from_params(size: usize, params: [u8; PARAMS_SIZE]) -> Self {
+        String::from_utf8_lossy(&params[..size])
+            .trim_end_matches('\0')
+            .to_string()
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Clone)]
 pub struct Message {
@@ -78,29 +146,32 @@ pub enum ReadWrite {
     Write = 1,
 }
 
-impl Into<u8> for ReadWrite {
-    fn into(self) -> u8 {
-        match self {
-            Self::Read => 0,
-            Self::Write => 1,
+impl From<ReadWrite> for u8 {
+    fn from(rw: ReadWrite) -> u8 {
+        match rw {
+            ReadWrite::Read => 0,
+            ReadWrite::Write => 1,
         }
     }
 }
 
 impl Debug for Message {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Message")
-            .field("id", &self.id)
-            .field("rw", &self.rw)
+        let mut f = f.debug_struct("Message");
+        match ProtocolID::try_from(self.id) {
+            Ok(protocol_id) => f.field("id", &protocol_id),
+            Err(id) => f.field("id", &id),
+        };
+        f.field("rw", &self.rw)
             .field("is_queued", &self.is_queued)
-            .field("params_len", &self.params_len)
+            .field("params", &&self.params[..self.params_len as usize])
             .finish()
     }
 }
 
 impl ToParams for () {
     fn to_params(&self) -> std::io::Result<(usize, [u8; PARAMS_SIZE])> {
-        unreachable!()
+        Ok((0, [0; PARAMS_SIZE]))
     }
 }
 
@@ -111,19 +182,29 @@ impl Message {
         is_queued: bool,
         params_value: &Option<T>,
     ) -> Self {
+        Self::try_new(protocol_id, rw, is_queued, params_value).unwrap()
+    }
+
+    /// Like `new`, but fails instead of panicking when the params do not fit in a packet.
+    pub fn try_new<T: ToParams>(
+        protocol_id: ProtocolID,
+        rw: ReadWrite,
+        is_queued: bool,
+        params_value: &Option<T>,
+    ) -> std::io::Result<Self> {
         let (size, params) = if let Some(p) = params_value {
-            p.to_params().unwrap()
+            p.to_params()?
         } else {
             (0, [0; PARAMS_SIZE])
         };
 
-        Self {
+        Ok(Self {
             id: protocol_id as u8,
             rw: rw.into(),
             is_queued: if is_queued { 1u8 } else { 0u8 },
             params_len: size as u8,
             params,
-        }
+        })
     }
 
     pub fn new_get_left_space() -> Self {
@@ -135,4 +216,32 @@ impl Message {
             params: [0u8; PARAMS_SIZE],
         }
     }
+
+    pub fn new_get_queue_index() -> Self {
+        Self {
+            id: ProtocolID::ProtocolQueuedCmdCurrentIndex as u8,
+            rw: 0,
+            is_queued: 0,
+            params_len: 0,
+            params: [0u8; PARAMS_SIZE],
+        }
+    }
+
+    /// The queue index carried by the ACK of a queued command.
+    pub fn queue_index(&self) -> u64 {
+        <u64 as FromParams>::from_params(self.params_len as usize, self.params)
+    }
+
+    /// An ACK for this queued command as if the controller had put it at `index`.
+    pub fn new_queue_ack(&self, index: u64) -> Self {
+        let mut params = [0u8; PARAMS_SIZE];
+        params[..8].copy_from_slice(&index.to_le_bytes());
+        Self {
+            id: self.id,
+            rw: self.rw,
+            is_queued: self.is_queued,
+            params_len: 8,
+            params,
+        }
+    }
 }
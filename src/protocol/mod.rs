@@ -1,3 +1,4 @@
+pub mod descriptor;
 pub mod message;
 pub mod packet;
 pub mod protocol_id;
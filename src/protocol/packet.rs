@@ -1,7 +1,8 @@
 use std::io::Write;
 
-use nom::bytes::complete::take;
-use nom::combinator::map;
+use nom::bytes::streaming::take;
+use nom::combinator::{map, verify};
+use nom::error::ErrorKind;
 use nom::sequence::tuple;
 
 use crate::protocol::message::{Message, PARAMS_SIZE};
@@ -54,7 +55,7 @@ impl Packet {
         let mut params = [0u8; PARAMS_SIZE];
         let params_len = (self.header.payload_len - 2) as usize;
         let mut params_buf = &mut params[..PARAMS_SIZE];
-        params_buf.write(&self.payload.params).unwrap();
+        params_buf.write_all(&self.payload.params).unwrap();
         Message {
             id: self.payload.id,
             rw: self.payload.ctrl & 0x01,
@@ -72,7 +73,7 @@ impl Packet {
         for i in 0..((header.payload_len - 2) as usize) {
             sum = sum.wrapping_add(payload.params[i]);
         }
-        0u8.wrapping_sub(sum) as u8
+        0u8.wrapping_sub(sum)
     }
 
     pub fn to_bytes(&self, mut buf: &mut [u8]) -> std::io::Result<usize> {
@@ -90,8 +91,14 @@ impl Packet {
 
     pub fn from_bytes(input: &[u8]) -> IResult<&[u8], Packet> {
         let (remain, header) = map(
-            tuple((take(1usize), take(1usize), take(1usize))),
-            |(x1, x2, x3): (&[u8], _, _)| PacketHeader {
+            tuple((
+                verify(take(1usize), |x: &[u8]| x[0] == SYNC_BYTE),
+                verify(take(1usize), |x: &[u8]| x[0] == SYNC_BYTE),
+                verify(take(1usize), |x: &[u8]| {
+                    x[0] >= 2 && x[0] as usize - 2 <= PARAMS_SIZE
+                }),
+            )),
+            |(x1, x2, x3): (&[u8], &[u8], &[u8])| PacketHeader {
                 sync_bytes: [x1[0], x2[0]],
                 payload_len: x3[0],
             },
@@ -107,6 +114,9 @@ impl Packet {
         )(remain)?;
 
         let (remain, checksum) = map(take(1usize), |x: &[u8]| x[0])(remain)?;
+        if checksum != Self::checksum(&header, &payload) {
+            return Err(nom::Err::Error((input, ErrorKind::Verify)));
+        }
 
         Ok((
             remain,
@@ -1,3 +1,6 @@
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+
 const PROTOCOL_FUNCTION_DEVICE_INFO_BASE: u8 = 0;
 const PROTOCOL_FUNCTION_POSE_BASE: u8 = 10;
 const PROTOCOL_FUNCTIONAL_ARM_BASE: u8 = 20;
@@ -22,7 +25,9 @@ const PROTOCOL_TEST_BASE: u8 = 220;
 
 const PROTOCOL_FUNCTION_QUEUED_CMD_BASE: u8 = 240;
 
+#[allow(clippy::identity_op)]
 #[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ProtocolID {
     // Device information
     ProtocolDeviceSN = PROTOCOL_FUNCTION_DEVICE_INFO_BASE + 0,
@@ -68,7 +73,6 @@ pub enum ProtocolID {
     ProtocolJOGLParams = PROTOCOL_FUNCTION_JOG_BASE + 4,
 
     // Function-PTP
-
     ProtocolPTPJointParams = PROTOCOL_FUNCTION_PTP_BASE + 0,
     ProtocolPTPCoordinateParams = PROTOCOL_FUNCTION_PTP_BASE + 1,
     ProtocolPTPJumpParams = PROTOCOL_FUNCTION_PTP_BASE + 2,
@@ -83,7 +87,6 @@ pub enum ProtocolID {
     ProtocolPTPPOWithLCmd = PROTOCOL_FUNCTION_PTP_BASE + 9,
 
     // Function-CP
-
     ProtocolCPParams = PROTOCOL_FUNCTION_CP_BASE + 0,
     ProtocolCPCmd = PROTOCOL_FUNCTION_CP_BASE + 1,
     ProtocolCPLECmd = PROTOCOL_FUNCTION_CP_BASE + 2,
@@ -103,7 +106,6 @@ pub enum ProtocolID {
     ProtocolTRIGCmd = PROTOCOL_FUNCTION_TRIG_BASE + 0,
 
     // Function-EIO
-
     ProtocolIOMultiplexing = PROTOCOL_FUNCTION_EIO_BASE + 0,
     ProtocolIODO = PROTOCOL_FUNCTION_EIO_BASE + 1,
     ProtocolIOPWM = PROTOCOL_FUNCTION_EIO_BASE + 2,
@@ -154,11 +156,124 @@ pub enum ProtocolID {
 
     // Function-QueuedCmd
     ProtocolQueuedCmdStartExec = PROTOCOL_FUNCTION_QUEUED_CMD_BASE + 0,
-    ProtocolQueuedCmdStopExec = PROTOCOL_FUNCTION_QUEUED_CMD_BASE  + 1,
+    ProtocolQueuedCmdStopExec = PROTOCOL_FUNCTION_QUEUED_CMD_BASE + 1,
     ProtocolQueuedCmdForceStopExec = PROTOCOL_FUNCTION_QUEUED_CMD_BASE + 2,
     ProtocolQueuedCmdStartDownload = PROTOCOL_FUNCTION_QUEUED_CMD_BASE + 3,
-    ProtocolQueuedCmdStopDownload = PROTOCOL_FUNCTION_QUEUED_CMD_BASE  + 4,
+    ProtocolQueuedCmdStopDownload = PROTOCOL_FUNCTION_QUEUED_CMD_BASE + 4,
     ProtocolQueuedCmdClear = PROTOCOL_FUNCTION_QUEUED_CMD_BASE + 5,
-    ProtocolQueuedCmdCurrentIndex = PROTOCOL_FUNCTION_QUEUED_CMD_BASE  + 6,
+    ProtocolQueuedCmdCurrentIndex = PROTOCOL_FUNCTION_QUEUED_CMD_BASE + 6,
     ProtocolQueuedCmdLeftSpace = PROTOCOL_FUNCTION_QUEUED_CMD_BASE + 7,
 }
+
+impl ProtocolID {
+    pub const ALL: [ProtocolID; 88] = [
+        ProtocolID::ProtocolDeviceSN,
+        ProtocolID::ProtocolDeviceName,
+        ProtocolID::ProtocolDeviceVersion,
+        ProtocolID::ProtocolDeviceWithL,
+        ProtocolID::ProtocolDeviceTime,
+        ProtocolID::ProtocolGetPose,
+        ProtocolID::ProtocolResetPose,
+        ProtocolID::ProtocolGetKinematics,
+        ProtocolID::ProtocolGetPoseL,
+        ProtocolID::ProtocolAlarmsState,
+        ProtocolID::ProtocolHOMEParams,
+        ProtocolID::ProtocolHOMECmd,
+        ProtocolID::ProtocolAutoLeveling,
+        ProtocolID::ProtocolHHTTrigMode,
+        ProtocolID::ProtocolHHTTrigOutputEnabled,
+        ProtocolID::ProtocolHHTTrigOutput,
+        ProtocolID::ProtocolArmOrientation,
+        ProtocolID::ProtocolEndEffectorParams,
+        ProtocolID::ProtocolEndEffectorLaser,
+        ProtocolID::ProtocolEndEffectorSuctionCup,
+        ProtocolID::ProtocolEndEffectorGripper,
+        ProtocolID::ProtocolJOGJointParams,
+        ProtocolID::ProtocolJOGCoordinateParams,
+        ProtocolID::ProtocolJOGCommonParams,
+        ProtocolID::ProtocolJOGCmd,
+        ProtocolID::ProtocolJOGLParams,
+        ProtocolID::ProtocolPTPJointParams,
+        ProtocolID::ProtocolPTPCoordinateParams,
+        ProtocolID::ProtocolPTPJumpParams,
+        ProtocolID::ProtocolPTPCommonParams,
+        ProtocolID::ProtocolPTPCmd,
+        ProtocolID::ProtocolPTPLParams,
+        ProtocolID::ProtocolPTPWithLCmd,
+        ProtocolID::ProtocolPTPJump2Params,
+        ProtocolID::ProtocolPTPPOCmd,
+        ProtocolID::ProtocolPTPPOWithLCmd,
+        ProtocolID::ProtocolCPParams,
+        ProtocolID::ProtocolCPCmd,
+        ProtocolID::ProtocolCPLECmd,
+        ProtocolID::ProtocolCPRHoldEnable,
+        ProtocolID::ProtocolCPCommonParams,
+        ProtocolID::ProtocolARCParams,
+        ProtocolID::ProtocolARCCmd,
+        ProtocolID::ProtocolCircleCmd,
+        ProtocolID::ProtocolARCCommonParams,
+        ProtocolID::ProtocolWAITCmd,
+        ProtocolID::ProtocolTRIGCmd,
+        ProtocolID::ProtocolIOMultiplexing,
+        ProtocolID::ProtocolIODO,
+        ProtocolID::ProtocolIOPWM,
+        ProtocolID::ProtocolIODI,
+        ProtocolID::ProtocolIOADC,
+        ProtocolID::ProtocolEMotor,
+        ProtocolID::ProtocolEMotorS,
+        ProtocolID::ProtocolColorSensor,
+        ProtocolID::ProtocolIRSwitch,
+        ProtocolID::ProtocolAngleSensorStaticError,
+        ProtocolID::ProtocolAngleSensorCoef,
+        ProtocolID::ProtocolBaseDecoderStaticError,
+        ProtocolID::ProtocolLRHandCalibrateValue,
+        ProtocolID::ProtocolWIFIConfigMode,
+        ProtocolID::ProtocolWIFISSID,
+        ProtocolID::ProtocolWIFIPassword,
+        ProtocolID::ProtocolWIFIIPAddress,
+        ProtocolID::ProtocolWIFINetmask,
+        ProtocolID::ProtocolWIFIGateway,
+        ProtocolID::ProtocolWIFIDNS,
+        ProtocolID::ProtocolWIFIConnectStatus,
+        ProtocolID::ProtocolFirmwareSwitch,
+        ProtocolID::ProtocolFirmwareMode,
+        ProtocolID::ProtocolLostStepSet,
+        ProtocolID::ProtocolLostStepDetect,
+        ProtocolID::ProtocolCheckUART4PeripheralsModel,
+        ProtocolID::ProtocolUART4PeripheralsEnabled,
+        ProtocolID::ProtocolFunctionPulseMode,
+        ProtocolID::ProtocolUserParams,
+        ProtocolID::ProtocolPTPTime,
+        ProtocolID::ProtocolServoPIDParams,
+        ProtocolID::ProtocolServoControlLoop,
+        ProtocolID::ProtocolSaveServoPIDParams,
+        ProtocolID::ProtocolQueuedCmdStartExec,
+        ProtocolID::ProtocolQueuedCmdStopExec,
+        ProtocolID::ProtocolQueuedCmdForceStopExec,
+        ProtocolID::ProtocolQueuedCmdStartDownload,
+        ProtocolID::ProtocolQueuedCmdStopDownload,
+        ProtocolID::ProtocolQueuedCmdClear,
+        ProtocolID::ProtocolQueuedCmdCurrentIndex,
+        ProtocolID::ProtocolQueuedCmdLeftSpace,
+    ];
+}
+
+impl TryFrom<u8> for ProtocolID {
+    type Error = u8;
+
+    /// Fails with `id` itself if no protocol has that id.
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        ProtocolID::ALL
+            .iter()
+            .copied()
+            .find(|protocol_id| *protocol_id as u8 == id)
+            .ok_or(id)
+    }
+}
+
+/// The name without the `Protocol` prefix, e.g. `PTPCmd`.
+impl Display for ProtocolID {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.descriptor().name)
+    }
+}
@@ -0,0 +1,624 @@
+//! Recording of the packets exchanged with the controller, and replay of such a recording in
+//! place of the arm, e.g. to reproduce a bug from the field without the hardware.
+//!
+//! `DobotBuilder::record` writes every packet sent or received along with the time since the
+//! connection was opened, and `DobotBuilder::replay` answers the commands from a recording
+//! instead of a serial port. The JSON lines format needs the `serde` feature.
+
+use crate::connector::{ConnectorError, Transport};
+use crate::protocol::packet::{Packet, MAX_PACKET_SIZE};
+use crate::runtime::Runtime;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use futures::future::{BoxFuture, FutureExt};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub(crate) const MAGIC: &[u8] = b"DOBOTREC\x01";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RecordFormat {
+    /// `DOBOTREC` and a version byte, then per packet its direction (0 sent, 1 received), the
+    /// microseconds since the start as a little endian u64, its length as a little endian u16
+    /// and its bytes.
+    Binary,
+    /// One object per packet, e.g.
+    /// `{"time_us":1520,"direction":"received","packet":"aaaa020a00f6"}`.
+    #[cfg(feature = "serde")]
+    JsonLines,
+}
+
+impl RecordFormat {
+    #[cfg(test)]
+    pub(crate) const ALL: &'static [RecordFormat] = &[
+        RecordFormat::Binary,
+        #[cfg(feature = "serde")]
+        RecordFormat::JsonLines,
+    ];
+}
+
+/// Which way a recorded packet went, seen from the host.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+pub(crate) struct Record {
+    pub(crate) direction: Direction,
+    pub(crate) time: Duration,
+    pub(crate) bytes: Vec<u8>,
+}
+
+/// The error that stopped a recording, shared between the `Recorder` and the `Dobot` it records.
+#[derive(Clone, Default)]
+pub(crate) struct RecordingError(Arc<Mutex<Option<std::io::Error>>>);
+
+impl RecordingError {
+    fn set(&self, error: std::io::Error) {
+        self.0.lock().unwrap().get_or_insert(error);
+    }
+
+    pub(crate) fn get(&self) -> Option<std::io::Error> {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|e| std::io::Error::new(e.kind(), e.to_string()))
+    }
+}
+
+/// Passes everything through to `inner` and writes down each packet on the way.
+///
+/// The packets are what matters, so a failure to write them down does not fail the exchange: it
+/// is kept for `Dobot::recording_error` and the recording stops there. Writes are buffered and
+/// flushed once the recorder is dropped.
+pub(crate) struct Recorder {
+    inner: Box<dyn Transport>,
+    // `None` once writing to it failed.
+    sink: Option<BufWriter<Box<dyn Write + Send>>>,
+    format: RecordFormat,
+    start: Instant,
+    error: RecordingError,
+}
+
+impl Recorder {
+    pub(crate) fn new(
+        inner: Box<dyn Transport>,
+        sink: Box<dyn Write + Send>,
+        format: RecordFormat,
+    ) -> std::io::Result<Self> {
+        let mut sink = BufWriter::new(sink);
+        if format == RecordFormat::Binary {
+            sink.write_all(MAGIC)?;
+        }
+        Ok(Self {
+            inner,
+            sink: Some(sink),
+            format,
+            start: Instant::now(),
+            error: RecordingError::default(),
+        })
+    }
+
+    pub(crate) fn error(&self) -> RecordingError {
+        self.error.clone()
+    }
+
+    fn record(&mut self, direction: Direction, packet: &Packet) {
+        if let Some(sink) = &mut self.sink {
+            if let Err(e) = write_record(sink, self.format, direction, self.start, packet) {
+                self.error.set(e);
+                self.sink = None;
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(sink) = &mut self.sink {
+            if let Err(e) = sink.flush() {
+                self.error.set(e);
+            }
+        }
+    }
+}
+
+fn write_record(
+    sink: &mut dyn Write,
+    format: RecordFormat,
+    direction: Direction,
+    start: Instant,
+    packet: &Packet,
+) -> std::io::Result<()> {
+    let mut buf = [0u8; MAX_PACKET_SIZE + 4];
+    let size = packet.to_bytes(&mut buf)?;
+    let record = Record {
+        direction,
+        time: start.elapsed(),
+        bytes: buf[..size].to_vec(),
+    };
+    match format {
+        RecordFormat::Binary => write_binary(sink, &record),
+        #[cfg(feature = "serde")]
+        RecordFormat::JsonLines => writeln!(sink, "{}", to_json(&record)),
+    }
+}
+
+/// Keeps a recording in memory until `persist` gives it a destination, so that nothing is
+/// written for a port that turns out not to be the arm looked for.
+#[derive(Clone, Default)]
+pub(crate) struct DeferredSink(Arc<Mutex<DeferredState>>);
+
+#[derive(Default)]
+struct DeferredState {
+    buffer: Vec<u8>,
+    destination: Option<Box<dyn Write + Send>>,
+}
+
+impl DeferredSink {
+    /// Writes what was recorded so far to `destination`, and everything after straight to it.
+    pub(crate) fn persist(&self, mut destination: Box<dyn Write + Send>) -> std::io::Result<()> {
+        let mut state = self.0.lock().unwrap();
+        destination.write_all(&state.buffer)?;
+        destination.flush()?;
+        state.buffer = vec![];
+        state.destination = Some(destination);
+        Ok(())
+    }
+}
+
+impl Write for DeferredSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.0.lock().unwrap();
+        match &mut state.destination {
+            Some(destination) => destination.write(buf),
+            None => {
+                state.buffer.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.0.lock().unwrap().destination {
+            Some(destination) => destination.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Transport for Recorder {
+    fn read_packet_with_timeout(
+        &mut self,
+        wait_duration: Duration,
+    ) -> BoxFuture<'_, std::io::Result<Option<Packet>>> {
+        async move {
+            let packet = self.inner.read_packet_with_timeout(wait_duration).await?;
+            if let Some(packet) = &packet {
+                self.record(Direction::Received, packet);
+            }
+            Ok(packet)
+        }
+        .boxed()
+    }
+
+    fn write_packet<'a>(&'a mut self, packet: &'a Packet) -> BoxFuture<'a, std::io::Result<usize>> {
+        async move {
+            let size = self.inner.write_packet(packet).await?;
+            self.record(Direction::Sent, packet);
+            Ok(size)
+        }
+        .boxed()
+    }
+
+    fn reconnect(&mut self, wait_duration: Duration) -> BoxFuture<'_, Result<(), ConnectorError>> {
+        self.inner.reconnect(wait_duration)
+    }
+}
+
+/// Plays the controller's side of a recording back.
+///
+/// Each packet written must be the next one sent in the recording, or the write fails with
+/// `ErrorKind::InvalidData`. The packets received after it are read back with the same delays
+/// as they were recorded with, so replies that came late, or not at all, time out again.
+pub(crate) struct Replay {
+    records: VecDeque<Record>,
+    // Packets the recorded session had read before its next write, but this one has not yet.
+    unread: VecDeque<Vec<u8>>,
+    // The last write, on this session's clock and on the recording's.
+    synced_at: Instant,
+    synced_time: Duration,
+    runtime: Arc<dyn Runtime>,
+}
+
+impl Replay {
+    pub(crate) fn new(source: impl Read, runtime: Arc<dyn Runtime>) -> std::io::Result<Self> {
+        Ok(Self {
+            records: read_records(source)?,
+            unread: VecDeque::new(),
+            synced_at: Instant::now(),
+            synced_time: Duration::from_secs(0),
+            runtime,
+        })
+    }
+
+    fn take_unread(&mut self) -> Option<Packet> {
+        let bytes = self.unread.pop_front()?;
+        parse_packet(&bytes).ok()
+    }
+}
+
+impl Transport for Replay {
+    fn read_packet_with_timeout(
+        &mut self,
+        wait_duration: Duration,
+    ) -> BoxFuture<'_, std::io::Result<Option<Packet>>> {
+        async move {
+            if let Some(packet) = self.take_unread() {
+                return Ok(Some(packet));
+            }
+            let due = match self.records.front() {
+                Some(record) if record.direction == Direction::Received => {
+                    self.synced_at + record.time.saturating_sub(self.synced_time)
+                }
+                _ => {
+                    self.runtime.delay(wait_duration).await;
+                    return Ok(None);
+                }
+            };
+            let wait = due.saturating_duration_since(Instant::now());
+            if wait > wait_duration {
+                self.runtime.delay(wait_duration).await;
+                return Ok(None);
+            }
+            self.runtime.delay(wait).await;
+            let record = self.records.pop_front().unwrap();
+            parse_packet(&record.bytes).map(Some)
+        }
+        .boxed()
+    }
+
+    fn write_packet<'a>(&'a mut self, packet: &'a Packet) -> BoxFuture<'a, std::io::Result<usize>> {
+        async move {
+            while let Some(record) = self.records.pop_front() {
+                if record.direction == Direction::Received {
+                    self.unread.push_back(record.bytes);
+                    continue;
+                }
+
+                let mut buf = [0u8; MAX_PACKET_SIZE + 4];
+                let size = packet.to_bytes(&mut buf)?;
+                if buf[..size] != record.bytes[..] {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "sent {} where the recording sent {} at {:?}",
+                            to_hex(&buf[..size]),
+                            to_hex(&record.bytes),
+                            record.time
+                        ),
+                    ));
+                }
+                // Replies are timed from the request, as the recorded ones were.
+                self.synced_at = Instant::now();
+                self.synced_time = record.time;
+                return Ok(size);
+            }
+            Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the recording has ended",
+            ))
+        }
+        .boxed()
+    }
+
+    fn reconnect(&mut self, _wait_duration: Duration) -> BoxFuture<'_, Result<(), ConnectorError>> {
+        async move { Ok(()) }.boxed()
+    }
+}
+
+fn parse_packet(bytes: &[u8]) -> std::io::Result<Packet> {
+    match Packet::from_bytes(bytes) {
+        Ok((_, packet)) => Ok(packet),
+        Err(_) => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not a packet", to_hex(bytes)),
+        )),
+    }
+}
+
+fn write_binary(sink: &mut dyn Write, record: &Record) -> std::io::Result<()> {
+    sink.write_u8(match record.direction {
+        Direction::Sent => 0,
+        Direction::Received => 1,
+    })?;
+    sink.write_u64::<LittleEndian>(record.time.as_micros() as u64)?;
+    sink.write_u16::<LittleEndian>(record.bytes.len() as u16)?;
+    sink.write_all(&record.bytes)
+}
+
+/// A record as a line of the JSON lines format.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonRecord {
+    time_us: u64,
+    direction: Direction,
+    packet: String,
+}
+
+#[cfg(feature = "serde")]
+fn to_json(record: &Record) -> String {
+    let record = JsonRecord {
+        time_us: record.time.as_micros() as u64,
+        direction: record.direction,
+        packet: to_hex(&record.bytes),
+    };
+    serde_json::to_string(&record).unwrap()
+}
+
+/// Reads either format, telling them apart by the magic bytes of the binary one.
+pub(crate) fn read_records(source: impl Read) -> std::io::Result<VecDeque<Record>> {
+    let mut source = BufReader::new(source);
+    if source.fill_buf()?.starts_with(MAGIC) {
+        source.consume(MAGIC.len());
+        read_binary(source)
+    } else {
+        read_json_lines(source)
+    }
+}
+
+#[cfg(feature = "serde")]
+fn read_json_lines(source: impl BufRead) -> std::io::Result<VecDeque<Record>> {
+    source
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| from_json(&line?))
+        .collect()
+}
+
+#[cfg(not(feature = "serde"))]
+fn read_json_lines(_source: impl BufRead) -> std::io::Result<VecDeque<Record>> {
+    Err(std::io::Error::new(
+        ErrorKind::InvalidData,
+        "not a binary recording, and JSON lines need the serde feature",
+    ))
+}
+
+fn read_binary(mut source: impl BufRead) -> std::io::Result<VecDeque<Record>> {
+    let mut records = VecDeque::new();
+    while !source.fill_buf()?.is_empty() {
+        let direction = match source.read_u8()? {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            direction => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown direction {}", direction),
+                ))
+            }
+        };
+        let time = Duration::from_micros(source.read_u64::<LittleEndian>()?);
+        let mut bytes = vec![0u8; source.read_u16::<LittleEndian>()? as usize];
+        source.read_exact(&mut bytes)?;
+        records.push_back(Record {
+            direction,
+            time,
+            bytes,
+        });
+    }
+    Ok(records)
+}
+
+/// Parses the lines `to_json` writes. Anything else is rejected rather than guessed at.
+#[cfg(feature = "serde")]
+fn from_json(line: &str) -> std::io::Result<Record> {
+    let invalid = || {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not a recorded packet", line),
+        )
+    };
+    let record: JsonRecord = serde_json::from_str(line).map_err(|_| invalid())?;
+    Ok(Record {
+        direction: record.direction,
+        time: Duration::from_micros(record.time_us),
+        bytes: from_hex(&record.packet).ok_or_else(invalid)?,
+    })
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+#[cfg(feature = "serde")]
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::types::PTPCmd;
+    use crate::api::{Dobot, DobotError};
+    use crate::emulator::{Emulator, Fault, SharedSink};
+    use crate::protocol::protocol_id::ProtocolID;
+    use crate::runtime::default_runtime;
+    use std::fs::File;
+
+    const WAIT_TIME: Duration = Duration::from_millis(50);
+
+    async fn record_session(format: RecordFormat, emulator: Emulator) -> Vec<u8> {
+        let sink = SharedSink::default();
+        let dobot = Dobot::builder()
+            .transport(Box::new(
+                Recorder::new(Box::new(emulator), Box::new(sink.clone()), format).unwrap(),
+            ))
+            .ack_timeout(WAIT_TIME)
+            .connect()
+            .await
+            .unwrap();
+        dobot.get_pose().await.unwrap();
+        dobot.move_to(PTPCmd::default()).accepted().await.unwrap();
+        dobot.get_pose().await.unwrap();
+        dobot.disconnect_dobot().await;
+        sink.contents()
+    }
+
+    async fn replay_session(recording: &[u8]) -> Dobot {
+        Dobot::builder()
+            .transport(Box::new(
+                Replay::new(recording, default_runtime().unwrap()).unwrap(),
+            ))
+            .ack_timeout(WAIT_TIME)
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn recording_replays_the_same_replies() {
+        for format in RecordFormat::ALL.iter() {
+            let recording = record_session(*format, Emulator::new()).await;
+            let dobot = replay_session(&recording).await;
+
+            assert_eq!(dobot.get_pose().await.unwrap().x, 1.0);
+            assert!(dobot.move_to(PTPCmd::default()).accepted().await.is_ok());
+            assert_eq!(dobot.get_pose().await.unwrap().x, 2.0);
+            assert!(matches!(
+                dobot.get_pose().await,
+                Err(DobotError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn lost_reply_times_out_again() {
+        let emulator = Emulator::new();
+        emulator.inject(ProtocolID::ProtocolGetPose, Fault::DropReply);
+        let recording = record_session(RecordFormat::Binary, emulator).await;
+        let dobot = replay_session(&recording).await;
+
+        assert_eq!(dobot.get_pose().await.unwrap().x, 2.0);
+        assert_eq!(dobot.diagnostics().timeouts, 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn json_lines_are_readable() {
+        let recording = record_session(RecordFormat::JsonLines, Emulator::new()).await;
+        let first = String::from_utf8(recording).unwrap();
+        let first = first.lines().next().unwrap();
+
+        assert!(first.starts_with(r#"{"time_us":"#));
+        assert!(first.ends_with(&format!(
+            r#""direction":"sent","packet":"aaaa02{:02x}00{:02x}"}}"#,
+            ProtocolID::ProtocolGetPose as u8,
+            0u8.wrapping_sub(ProtocolID::ProtocolGetPose as u8)
+        )));
+    }
+
+    struct Failing;
+
+    impl Write for Failing {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::Other.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_sink_stops_the_recording_but_not_the_exchange() {
+        let recorder = Recorder::new(
+            Box::new(Emulator::new()),
+            Box::new(Failing),
+            RecordFormat::Binary,
+        )
+        .unwrap();
+        let error = recorder.error();
+        let dobot = Dobot::builder()
+            .transport(Box::new(recorder))
+            .ack_timeout(WAIT_TIME)
+            .connect()
+            .await
+            .unwrap();
+
+        assert_eq!(dobot.get_pose().await.unwrap().x, 1.0);
+        assert_eq!(dobot.get_pose().await.unwrap().x, 2.0);
+        dobot.disconnect_dobot().await;
+        assert!(error.get().is_some());
+    }
+
+    #[tokio::test]
+    async fn recording_error_is_kept_for_the_dobot() {
+        let dobot = Dobot::builder()
+            .transport(Box::new(Emulator::new()))
+            .record("/dev/full", RecordFormat::Binary)
+            .ack_timeout(WAIT_TIME)
+            .connect()
+            .await
+            .unwrap();
+        assert!(dobot.recording_error().is_none());
+
+        assert_eq!(dobot.get_pose().await.unwrap().x, 1.0);
+        dobot.disconnect_dobot().await;
+        assert!(dobot.recording_error().is_some());
+    }
+
+    #[tokio::test]
+    async fn recording_is_only_created_for_the_arm_picked() {
+        let path = std::env::temp_dir().join(format!("dobot-api-{}.rec", std::process::id()));
+        let connect = |serial_number| {
+            Dobot::builder()
+                .transport(Box::new(Emulator::new()))
+                .serial_number(serial_number)
+                .record(&path, RecordFormat::Binary)
+                .connect()
+        };
+
+        assert!(matches!(
+            connect("another").await,
+            Err(DobotError::PortNotFound)
+        ));
+        assert!(!path.exists());
+
+        let dobot = connect(crate::emulator::SERIAL_NUMBER).await.unwrap();
+        dobot.disconnect_dobot().await;
+        let records = read_records(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].bytes[3], ProtocolID::ProtocolDeviceSN as u8);
+    }
+
+    #[tokio::test]
+    async fn diverging_command_fails() {
+        let recording = record_session(RecordFormat::Binary, Emulator::new()).await;
+        let dobot = replay_session(&recording).await;
+
+        assert!(matches!(
+            dobot.get_alarms_state().await,
+            Err(DobotError::Io(e)) if e.kind() == ErrorKind::InvalidData
+        ));
+    }
+}
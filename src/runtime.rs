@@ -0,0 +1,74 @@
+//! The few executor services the crate needs, so that it runs on tokio as well as on async-std.
+//!
+//! `DobotBuilder` picks `TokioRuntime` or `AsyncStdRuntime` depending on the enabled features,
+//! and `DobotBuilder::runtime` plugs in any other executor.
+
+use futures::future::{BoxFuture, FutureExt};
+use futures::{pin_mut, select};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub trait Runtime: Send + Sync {
+    /// Runs `future` in the background until it completes.
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Runs on the tokio runtime the `Dobot` is created on.
+#[cfg(feature = "tokio-runtime")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioRuntime;
+
+#[cfg(feature = "tokio-runtime")]
+impl Runtime for TokioRuntime {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::spawn(future);
+    }
+
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::delay_for(duration).boxed()
+    }
+}
+
+#[cfg(feature = "async-std-runtime")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "async-std-runtime")]
+impl Runtime for AsyncStdRuntime {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        async_std::task::spawn(future);
+    }
+
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        async_std::task::sleep(duration).boxed()
+    }
+}
+
+/// The runtime of the enabled feature, tokio taking precedence if both are.
+pub(crate) fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    #[cfg(feature = "tokio-runtime")]
+    return Some(Arc::new(TokioRuntime));
+    #[cfg(all(feature = "async-std-runtime", not(feature = "tokio-runtime")))]
+    return Some(Arc::new(AsyncStdRuntime));
+    #[cfg(not(any(feature = "tokio-runtime", feature = "async-std-runtime")))]
+    return None;
+}
+
+/// Resolves to `None` if `future` did not complete within `duration`.
+pub(crate) async fn timeout<F: Future>(
+    runtime: &dyn Runtime,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    let future = future.fuse();
+    let delay = runtime.delay(duration).fuse();
+    pin_mut!(future, delay);
+
+    select! {
+        output = future => Some(output),
+        () = delay => None,
+    }
+}